@@ -0,0 +1,22 @@
+#!/bin/bash
+
+# Times a release build over the generate_test_data.sh dataset for each shard count.
+# Usage: ./bench_shards.sh [rows] [shard counts...]
+rows=${1:-2000000}
+shift
+shard_counts=${*:-1 2 4 8}
+
+data=$(mktemp)
+trap 'rm -f "$data"' EXIT
+
+./generate_test_data.sh "$rows" > "$data"
+cargo build --release -q
+
+echo "rows,shards,millis"
+for shards in $shard_counts
+do
+   start=$(date +%s%N)
+   ./target/release/turboencabulator "$data" --shards "$shards" > /dev/null
+   end=$(date +%s%N)
+   echo "$rows,$shards,$(( (end - start) / 1000000 ))"
+done
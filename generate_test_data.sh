@@ -1,9 +1,10 @@
 #!/bin/bash
 
-
+# Usage: ./generate_test_data.sh [rows]
+rows=${1:-50000000}
 
 echo "type, client,tx,amount"
-for ((a=1; a <= 50000000 ; a++))
+for ((a=1; a <= rows ; a++))
 do
    client=$((a%100))
    echo "deposit,$client,$a,1.1111"
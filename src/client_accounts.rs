@@ -1,221 +1,1086 @@
-extern crate clap;
+use std::collections::{BTreeMap, HashMap};
 
-use std::collections::{HashMap, HashSet};
-use std::error::Error;
-
-use csv::Writer;
-use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::ser::SerializeSeq;
-use serde::{Serialize, Serializer};
 
-use crate::transaction::{Transaction, TransactionHistoryRecord, TransactionType};
+use crate::config::{EngineConfig, WithdrawalDisputePolicy};
+use crate::error::EngineError;
+use crate::history::{HistoryStore, MemoryHistory};
+use crate::output::{self, OutputFormat};
+use crate::retention::{IdRanges, Kept, Retention};
+use crate::transaction::{
+    Asset, DisputeState, Transaction, TransactionHistoryRecord, TransactionType,
+};
 
 #[derive(Debug)]
 /// Struct representing current state of client account.
 ///
 /// `id`: unique client id
-/// `total`: the current value of the account
-/// `locked`: if the account had a charge back, it will be marked locked.
-/// `transaction_history`: a collection of all successfully applied transactions.
-/// `disputed`: a set of disputed transaction ids in `transaction_history`.
+/// `balances`: the current value of the account in each asset it holds.
+/// `locked`: if the account had a charge back, it will be marked locked until an `unlock`. It locks every asset.
+/// `disputed`: the open disputes, each with a copy of the disputed transaction so nothing needs to reach the history.
+/// `held`: what the open disputes hold in each asset, kept as they open and close so `held()` needn't walk them.
+/// `transactions`: how many transactions have been applied to the account, rejected ones aren't counted.
+///
+/// The history of applied transactions is kept per engine in a `HistoryStore`, not here.
 ///
-/// `held()`: sum of disputed transactions.
-/// `available()`: total funds less held funds.
-struct ClientAccount {
+/// `held_of()`: what the open disputes hold in an asset.
+/// `available_of()`: total funds in an asset less held funds.
+/// `spendable_of()`: what a withdrawal or transfer can take, available funds less what disputed withdrawals released.
+/// `total()`, `held()` and `available()` are the same for the default asset, all a single asset input has.
+pub struct ClientAccount {
     id: u16,
-    total: Decimal, // 129 bit. tested w/ floats but floating point imprecision appears
+    balances: BTreeMap<Asset, Decimal>, // 128 bit. tested w/ floats but floating point imprecision appears
     locked: bool,
-    transaction_history: HashMap<u32, TransactionHistoryRecord>,
-    disputed: HashSet<u32>,
+    disputed: HashMap<u32, Dispute>,
+    held: BTreeMap<Asset, Held>,
+    transactions: u64,
 }
 
-impl ClientAccount {
-    /// returns the total disputed funds (deposits only! withdrawals are ignored)
-    fn held(&self) -> Decimal {
-        let mut held: Decimal = dec!(0.0);
+/// What the open disputes in one asset hold, and how many there are.
+/// `released` is what disputed withdrawals hold negative under `HoldNegative`. It's available but can't be spent,
+/// as a resolve takes it back.
+/// It's dropped once the last closes, so an asset starts from zero with each run of disputes as it would be if added up afresh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Held {
+    amount: Decimal,
+    released: Decimal,
+    disputes: usize,
+}
 
-        for txid in self.disputed.iter() {
-            match self.transaction_history.get(txid) {
-                Some(hist) if hist.typ == TransactionType::Deposit => {
-                    held += Decimal::from_f64(hist.amount).unwrap()
-                }
-                _ => {}
+impl Held {
+    fn new() -> Held {
+        Held {
+            amount: dec!(0.0),
+            released: Decimal::ZERO,
+            disputes: 0,
+        }
+    }
+
+    fn add(&mut self, dispute: &Dispute) {
+        match dispute.hold {
+            Hold::All => self.amount += dispute.held(),
+            Hold::Nothing => (),
+            Hold::Negative => {
+                self.amount += dispute.held();
+                self.released += dispute.record.amount;
+            }
+        }
+        self.disputes += 1;
+    }
+
+    fn remove(&mut self, dispute: &Dispute) {
+        match dispute.hold {
+            Hold::All => self.amount -= dispute.held(),
+            Hold::Nothing => (),
+            Hold::Negative => {
+                self.amount -= dispute.held();
+                self.released -= dispute.record.amount;
+            }
+        }
+        self.disputes -= 1;
+    }
+}
+
+/// An open dispute on an account.
+///
+/// `record`: a copy of the disputed transaction. Its amount is only the part still disputed,
+/// as disputes, resolves and chargebacks can each be for part of a transaction.
+/// `hold`: how the account holds it.
+#[derive(Debug, Clone)]
+struct Dispute {
+    record: TransactionHistoryRecord,
+    hold: Hold,
+}
+
+/// How an account holds an open dispute, fixed when it's opened.
+///
+/// `All`: the disputed amount is held, as for a deposit or a transfer the account received.
+/// `Nothing`: as for a transfer the account sent, or a withdrawal unless the policy holds it negative.
+/// `Negative`: the disputed amount is held as a negative, making it available again, for a withdrawal under `HoldNegative`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hold {
+    All,
+    Nothing,
+    Negative,
+}
+
+impl Dispute {
+    /// The dispute as opened on the account that owns the transaction.
+    fn new(record: TransactionHistoryRecord, policy: WithdrawalDisputePolicy) -> Dispute {
+        let hold = match record.typ {
+            TransactionType::Deposit => Hold::All,
+            TransactionType::Withdrawal if policy == WithdrawalDisputePolicy::HoldNegative => {
+                Hold::Negative
             }
+            // a transfer is held by the client it went to, see `received`.
+            _ => Hold::Nothing,
+        };
+        Dispute { record, hold }
+    }
+
+    /// The copy of a transfer's dispute kept by the client it went to.
+    fn received(record: TransactionHistoryRecord) -> Dispute {
+        Dispute {
+            record,
+            hold: Hold::All,
+        }
+    }
+
+    fn held(&self) -> Decimal {
+        match self.hold {
+            Hold::All => self.record.amount,
+            Hold::Nothing => Decimal::ZERO,
+            Hold::Negative => -self.record.amount,
+        }
+    }
+}
+
+impl ClientAccount {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Number of disputes still open.
+    pub fn open_disputes(&self) -> usize {
+        self.disputed.len()
+    }
+
+    pub fn transactions(&self) -> u64 {
+        self.transactions
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total_of(Asset::default())
+    }
+
+    pub fn total_of(&self, asset: Asset) -> Decimal {
+        self.balances.get(&asset).copied().unwrap_or(dec!(0.0))
+    }
+
+    /// Every asset the account has held, in order.
+    pub fn assets(&self) -> impl Iterator<Item = Asset> + '_ {
+        self.balances.keys().copied()
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held_of(Asset::default())
+    }
+
+    /// returns the total disputed funds in an asset. Disputed withdrawals only count as the `WithdrawalDisputePolicy` says,
+    /// as a negative if at all. A disputed transfer is held by the client it went to, not the one that sent it.
+    pub fn held_of(&self, asset: Asset) -> Decimal {
+        self.held.get(&asset).map_or(dec!(0.0), |held| held.amount)
+    }
+
+    /// A snapshot per asset held. An account that never held anything still gets one for the default asset.
+    pub fn snapshots(&self) -> Vec<AccountSnapshot> {
+        let snapshot = |asset| AccountSnapshot {
+            client: self.id,
+            asset,
+            available: self.available_of(asset),
+            held: self.held_of(asset),
+            total: self.total_of(asset),
+            locked: self.locked,
+            open_disputes: self.held.get(&asset).map_or(0, |held| held.disputes),
+            transactions: self.transactions,
+        };
+
+        match self.balances.is_empty() {
+            true => vec![snapshot(Asset::default())],
+            false => self.assets().map(snapshot).collect(),
         }
+    }
 
-        held
+    pub fn available(&self) -> Decimal {
+        self.available_of(Asset::default())
     }
 
     /// available returns a positive value if funds are available.
-    /// It's calculated based on total funds less all disputed funds.
-    fn available(&self) -> Decimal {
-        let res = self.total - self.held();
+    /// It's calculated based on total funds less all disputed funds, in the one asset.
+    pub fn available_of(&self, asset: Asset) -> Decimal {
+        let res = self.total_of(asset) - self.held_of(asset);
         res.max(dec!(0.0))
     }
+
+    /// What a withdrawal or transfer can take. Funds a disputed withdrawal made available again aren't the client's
+    /// until it's charged back.
+    fn spendable_of(&self, asset: Asset) -> Decimal {
+        self.available_of(asset)
+            - self
+                .held
+                .get(&asset)
+                .map_or(Decimal::ZERO, |held| held.released)
+    }
+
+    fn balance(&mut self, asset: Asset) -> &mut Decimal {
+        self.balances.entry(asset).or_insert(dec!(0.0))
+    }
+}
+
+/// Point in time view of a client account in one asset, detached from the engine so it can be kept or sent elsewhere.
+///
+/// `open_disputes` and `transactions` are extras, only written when asked for.
+/// `open_disputes` counts the asset's disputes, `transactions` and `locked` are for the whole account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountSnapshot {
+    pub client: u16,
+    pub asset: Asset,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    pub open_disputes: usize,
+    pub transactions: u64,
 }
 
-/// Serialize will add an available field and will not serialize the transaction_history.
-impl Serialize for ClientAccount {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut seq = serializer.serialize_seq(Some(5))?;
-        seq.serialize_element(&self.id)?;
-        seq.serialize_element(&self.available())?;
-        seq.serialize_element(&self.held())?;
-        seq.serialize_element(&self.total)?;
-        seq.serialize_element(&self.locked)?;
-        seq.end()
+impl AccountSnapshot {
+    /// Rounds the amounts to the configured scale for writing out.
+    /// Available is worked out again from the rounded total and held so the three still add up.
+    pub fn round(&mut self, config: &EngineConfig) {
+        self.total = config.round_output(self.total);
+        self.held = config.round_output(self.held);
+        self.available = config.round_output((self.total - self.held).max(Decimal::ZERO));
     }
 }
 
+/// Writes account snapshots as csv to the specified Writer.
+/// Will fail and return error if one is encountered.
+pub fn write_csv<T: std::io::Write>(
+    snapshots: &[AccountSnapshot],
+    writer: T,
+) -> Result<(), EngineError> {
+    output::write_accounts(snapshots, OutputFormat::Csv, false, writer)
+}
+
 impl ClientAccount {
     fn new(id: u16) -> ClientAccount {
         ClientAccount {
             id,
             disputed: Default::default(),
-            total: dec!(0.0),
+            held: BTreeMap::new(),
+            balances: BTreeMap::new(),
             locked: false,
-            transaction_history: Default::default(),
+            transactions: 0,
         }
     }
 
-    fn update(&mut self, tx: Transaction) {
+    /// Applies a transaction to the account.
+    ///
+    /// Returns an error when the transaction is ignored and nothing changes.
+    /// A failed withdrawal is still recorded in the history so its id can't be reused.
+    /// Transaction ids are unique across clients, so an id another client used is a duplicate,
+    /// and disputes, resolutions and chargebacks must name the client that owns the transaction.
+    /// Each of those moves the transaction on through its `DisputeState`, which the history keeps.
+    /// Transfers touch two accounts so they're applied by `ClientAccounts`.
+    fn update(
+        &mut self,
+        history: &mut dyn HistoryStore,
+        config: &EngineConfig,
+        tx: Transaction,
+    ) -> Result<(), EngineError> {
+        let result = self.apply(history, config, tx);
+        #[cfg(test)]
+        self.check_held(history);
+        result
+    }
+
+    fn apply(
+        &mut self,
+        history: &mut dyn HistoryStore,
+        config: &EngineConfig,
+        tx: Transaction,
+    ) -> Result<(), EngineError> {
         match tx.typ {
-            TransactionType::Deposit
-                if !self.transaction_history.contains_key(&tx.tx) && tx.amount.is_some() =>
+            TransactionType::Deposit | TransactionType::Withdrawal
+                if history.contains(tx.tx)? =>
             {
-                self.total += Decimal::from_f64(tx.amount.unwrap()).unwrap();
-                self.transaction_history.insert(
-                    tx.tx,
-                    TransactionHistoryRecord {
-                        typ: tx.typ,
-                        amount: tx.amount.unwrap(),
-                    },
-                );
+                Err(EngineError::DuplicateTx(tx.tx))
             }
 
-            TransactionType::Withdrawal
-                if !self.transaction_history.contains_key(&tx.tx) && tx.amount.is_some() =>
-            {
-                let tx_amount = Decimal::from_f64(tx.amount.unwrap()).unwrap();
-                if self.available() - tx_amount >= dec!(0.0) {
-                    self.total -= tx_amount;
-                    self.transaction_history.insert(
-                        tx.tx,
-                        TransactionHistoryRecord {
-                            typ: tx.typ,
-                            amount: tx.amount.unwrap(),
-                        },
-                    );
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let amount = tx.amount.ok_or(EngineError::MissingAmount(tx.tx))?;
+                if amount.is_sign_negative() {
+                    return Err(EngineError::InvalidAmount(tx.tx));
+                }
+
+                if tx.typ == TransactionType::Deposit {
+                    *self.balance(tx.asset) += amount;
+                } else if self.spendable_of(tx.asset) - amount >= dec!(0.0) {
+                    *self.balance(tx.asset) -= amount;
                 } else {
-                    self.transaction_history.insert(
+                    history.insert(
                         tx.tx,
                         TransactionHistoryRecord {
+                            client: self.id,
                             typ: TransactionType::FailedWithdrawal,
-                            amount: tx.amount.unwrap(),
+                            amount,
+                            asset: tx.asset,
+                            to: None,
+                            state: DisputeState::Settled,
                         },
-                    );
+                    )?;
+                    return Err(EngineError::InsufficientFunds(tx.tx));
                 }
+
+                history.insert(
+                    tx.tx,
+                    TransactionHistoryRecord {
+                        client: self.id,
+                        typ: tx.typ,
+                        amount,
+                        asset: tx.asset,
+                        to: None,
+                        state: DisputeState::Settled,
+                    },
+                )?;
+                Ok(())
+            }
+
+            // the client a transfer went to holds a copy of its dispute, but only the sender can settle it.
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+                if self
+                    .disputed
+                    .get(&tx.tx)
+                    .is_some_and(|dispute| dispute.record.client != self.id) =>
+            {
+                Err(EngineError::WrongClient(tx.tx))
+            }
+
+            TransactionType::Dispute if self.disputed.contains_key(&tx.tx) => {
+                Err(EngineError::AlreadyDisputed(tx.tx))
             }
 
-            TransactionType::Dispute => {
-                // look for a transaction that was applied. If it exists then insert as disputed.
-                if self.transaction_history.get(&tx.tx).is_some() {
-                    self.disputed.insert(tx.tx);
+            // a dispute must reference a transaction this client applied.
+            TransactionType::Dispute => match history.get(tx.tx)? {
+                Some(record) if record.client != self.id => Err(EngineError::WrongClient(tx.tx)),
+                // a failed withdrawal or transfer moved nothing, it's only kept for its id.
+                Some(record) if record.typ == TransactionType::FailedWithdrawal => {
+                    Err(EngineError::NotDisputable(tx.tx))
+                }
+                Some(record)
+                    if record.typ == TransactionType::Withdrawal
+                        && config.withdrawal_disputes == WithdrawalDisputePolicy::Reject =>
+                {
+                    Err(EngineError::WithdrawalDispute(tx.tx))
+                }
+                Some(mut record) => {
+                    let state = record.state;
+                    record.state = state.next(&tx.typ, false, config.redispute, tx.tx)?;
+                    // a chargeback takes what it reverses off the transaction, so there may be nothing left.
+                    if record.amount.is_zero() && state == DisputeState::ChargedBack {
+                        return Err(EngineError::DisputeClosed(tx.tx, state));
+                    }
+
+                    let disputed = TransactionHistoryRecord {
+                        amount: portion(&tx, record.amount)?,
+                        ..record.clone()
+                    };
+                    history.insert(tx.tx, record)?;
+                    self.open(tx.tx, Dispute::new(disputed, config.withdrawal_disputes));
+                    Ok(())
+                }
+                None => Err(EngineError::UnknownTx(tx.tx)),
+            },
+
+            // resolutions and chargebacks too, but only disputed ones are worth a lookup.
+            TransactionType::Resolve | TransactionType::Chargeback
+                if !self.disputed.contains_key(&tx.tx) =>
+            {
+                match history.get(tx.tx)? {
+                    Some(record) if record.client != self.id => {
+                        Err(EngineError::WrongClient(tx.tx))
+                    }
+                    Some(record) => match record.state {
+                        DisputeState::Resolved | DisputeState::ChargedBack => {
+                            Err(EngineError::DisputeClosed(tx.tx, record.state))
+                        }
+                        _ => Err(EngineError::NotDisputed(tx.tx)),
+                    },
+                    None => Err(EngineError::UnknownTx(tx.tx)),
                 }
             }
 
             TransactionType::Resolve => {
-                self.disputed.remove(&tx.tx);
+                self.settle(history, config, &tx)?;
+                Ok(())
             }
 
-            TransactionType::Chargeback if self.disputed.contains(&tx.tx) => {
-                if let Some(history) = self.transaction_history.get(&tx.tx) {
-                    self.disputed.remove(&tx.tx);
+            TransactionType::Chargeback => {
+                if let Some(history) = self.settle(history, config, &tx)? {
                     self.locked = true;
 
+                    // in the asset of the disputed transaction, whatever the chargeback's row says.
                     match history.typ {
-                        TransactionType::Deposit => {
-                            self.total -= Decimal::from_f64(history.amount).unwrap()
+                        TransactionType::Deposit => *self.balance(history.asset) -= history.amount,
+                        TransactionType::Withdrawal if config.withdrawal_disputes.credits() => {
+                            *self.balance(history.asset) += history.amount
                         }
-                        TransactionType::Withdrawal => {
-                            self.total += Decimal::from_f64(history.amount).unwrap()
-                        } // TODO do we actually want to debit these?
-                        _ => (), // shouldn't happen.
+                        TransactionType::Withdrawal => (), // the policy leaves it withdrawn.
+                        // the sender gets it back, `ClientAccounts` takes it from the client it went to.
+                        TransactionType::Transfer => *self.balance(history.asset) += history.amount,
+                        _ => (), // failed withdrawals can't be disputed.
                     }
                 }
+                Ok(())
+            }
+
+            TransactionType::Unlock if !self.locked => Err(EngineError::NotLocked(self.id)),
+
+            TransactionType::Unlock => {
+                self.locked = false;
+                Ok(())
+            }
+
+            TransactionType::Transfer => Err(EngineError::InvalidTransfer(tx.tx)),
+
+            TransactionType::Unknown(typ) => Err(EngineError::UnknownType(typ)),
+
+            // only ever recorded in the history, never read from input.
+            TransactionType::FailedWithdrawal => {
+                Err(EngineError::UnknownType("failed_withdrawal".into()))
             }
-            _ => (), // any unknown type, or undisputed resolve or chargeback.
+        }
+    }
+
+    /// Takes the part a resolve or chargeback is for off its dispute, closing the dispute once nothing's left.
+    /// The history keeps the new state, and a chargeback takes what it reverses off the transaction there.
+    /// Returns the disputed transaction with just the amount settled.
+    fn settle(
+        &mut self,
+        history: &mut dyn HistoryStore,
+        config: &EngineConfig,
+        tx: &Transaction,
+    ) -> Result<Option<TransactionHistoryRecord>, EngineError> {
+        let disputed = match self.disputed.get(&tx.tx) {
+            Some(dispute) => &dispute.record,
+            None => return Ok(None),
         };
+        let amount = portion(tx, disputed.amount)?;
+        let closes = amount == disputed.amount;
+        let state = disputed
+            .state
+            .next(&tx.typ, closes, config.redispute, tx.tx)?;
+
+        if let Some(mut record) = history.get(tx.tx)? {
+            record.state = state;
+            if tx.typ == TransactionType::Chargeback {
+                record.amount -= amount;
+            }
+            history.insert(tx.tx, record)?;
+        }
+
+        let mut dispute = self.close(tx.tx).unwrap();
+        if !closes {
+            dispute.record.amount -= amount;
+            self.open(tx.tx, dispute.clone());
+        }
+        dispute.record.amount = amount;
+        Ok(Some(dispute.record))
+    }
+
+    /// Opens a dispute, or replaces it, holding what it holds.
+    fn open(&mut self, tx: u32, dispute: Dispute) {
+        self.close(tx);
+        self.held
+            .entry(dispute.record.asset)
+            .or_insert_with(Held::new)
+            .add(&dispute);
+        self.disputed.insert(tx, dispute);
+    }
+
+    /// Closes a dispute, releasing what it held, and returns it.
+    fn close(&mut self, tx: u32) -> Option<Dispute> {
+        let dispute = self.disputed.remove(&tx)?;
+        let asset = dispute.record.asset;
+        let held = self.held.get_mut(&asset).expect("an open dispute is held");
+        held.remove(&dispute);
+        if held.disputes == 0 {
+            self.held.remove(&asset);
+        }
+        Some(dispute)
+    }
+
+    /// Adds `held` up again from the open disputes, checking each against the history, and asserts it's what was kept.
+    /// Tests run it after every transaction, it's the walk keeping `held` saves.
+    #[cfg(test)]
+    fn check_held(&self, history: &mut dyn HistoryStore) {
+        let mut expected = BTreeMap::new();
+        for (tx, dispute) in &self.disputed {
+            // the copy held by the client a transfer went to may be on another shard than the transfer.
+            let record = match history.get(*tx).unwrap() {
+                Some(record) => record,
+                None if dispute.record.client != self.id => dispute.record.clone(),
+                None => panic!("disputes are in the history"),
+            };
+            assert!(record.client == self.id || record.to == Some(self.id));
+            assert_eq!(record.state, DisputeState::Disputed);
+            assert!(dispute.record.amount <= record.amount);
+
+            expected
+                .entry(dispute.record.asset)
+                .or_insert_with(Held::new)
+                .add(dispute);
+        }
+        assert_eq!(expected, self.held, "client {} held", self.id);
+    }
+}
+
+/// The part of `limit` a dispute, resolve or chargeback is for: its own amount if it has one, otherwise all of it.
+/// An amount has to be more than zero and can't be more than `limit`.
+fn portion(tx: &Transaction, limit: Decimal) -> Result<Decimal, EngineError> {
+    match tx.amount {
+        None => Ok(limit),
+        Some(amount) if amount > Decimal::ZERO && amount <= limit => Ok(amount),
+        Some(_) => Err(EngineError::InvalidAmount(tx.tx)),
+    }
+}
+
+/// What a shard router knows about a transaction's id that the shard it's routed to can't see,
+/// when the transaction reuses an id first used by a client on another shard.
+///
+/// `Owned`: the other client's transaction is still in that shard's history.
+/// `Evicted`: it's been evicted from that shard's history by the `RetentionPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Elsewhere {
+    Owned,
+    Evicted,
+}
+
+/// What a shard router knows about a transaction that the shard it's routed to can't see.
+///
+/// `elsewhere`: the id was first used by a client on another shard.
+/// `recipient_accepts`: for a transfer to a client on another shard, whether that client's account takes deposits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Routed {
+    pub elsewhere: Option<Elsewhere>,
+    pub recipient_accepts: Option<bool>,
+}
+
+/// The part of a transaction that falls to the client a transfer went to, when that client is on another shard:
+/// the transfer's credit, or the client's copy of a dispute on it. `ClientAccounts::receive` applies it there.
+///
+/// `amount`: added to the client's total, the transfer itself or less what a chargeback takes back.
+/// `credit`: whether it's the transfer itself, which counts as one of the client's transactions.
+/// `disputed`: the client's copy of the dispute as it is now, None if there's none open.
+#[derive(Debug, Clone)]
+pub struct Handoff {
+    tx: u32,
+    to: u16,
+    asset: Asset,
+    amount: Decimal,
+    credit: bool,
+    disputed: Option<TransactionHistoryRecord>,
+}
+
+impl Handoff {
+    /// The client it's for.
+    pub fn to(&self) -> u16 {
+        self.to
     }
 }
 
+/// All client accounts, keyed on client id, the config they're run with, the history they share
+/// and how long it's kept for disputes.
 #[derive(Debug)]
 pub struct ClientAccounts {
     map: HashMap<u16, ClientAccount>,
+    config: EngineConfig,
+    history: Box<dyn HistoryStore>,
+    retention: Retention,
+    handoff: Option<Handoff>,
+}
+
+impl Default for ClientAccounts {
+    fn default() -> Self {
+        ClientAccounts::with_config(EngineConfig::default())
+    }
 }
 
 impl ClientAccounts {
     pub fn new() -> ClientAccounts {
+        ClientAccounts::default()
+    }
+
+    /// Keeps the transaction history in memory.
+    pub fn with_config(config: EngineConfig) -> ClientAccounts {
+        ClientAccounts::with_store(config, Box::new(MemoryHistory::new()))
+    }
+
+    /// Keeps the transaction history in the given store, eg a `DiskHistory` for inputs bigger than memory.
+    pub fn with_store(config: EngineConfig, history: Box<dyn HistoryStore>) -> ClientAccounts {
         ClientAccounts {
             map: HashMap::new(),
+            retention: Retention::new(config.retention),
+            config,
+            history,
+            handoff: None,
         }
     }
 
-    // TODO no failures
-    pub fn update(&mut self, tx: Transaction) -> Result<(), Box<dyn Error>> {
-        match self.map.get_mut(&tx.client) {
-            None => {
-                let mut acct = ClientAccount::new(tx.client);
-                acct.update(tx);
-                self.map.insert(acct.id, acct);
+    /// Applies a transaction to its client's account, opening the account if it's new.
+    /// Locked accounts only accept what the configured `LockPolicy` permits.
+    /// Transaction ids are global: the history is shared by every account here, so ids can't be reused across clients.
+    /// Transactions that have left the dispute window under the `RetentionPolicy` are evicted from the history,
+    /// but their ids are remembered so they're still duplicates.
+    ///
+    /// Returns an error describing why the transaction was ignored, if it was.
+    pub fn update(&mut self, tx: Transaction) -> Result<(), EngineError> {
+        self.update_routed(tx, Routed::default())
+    }
+
+    /// As `update`, for a transaction routed here from a router that knows what `routed` says.
+    /// It's applied or rejected just as it would be if every client were here, opening its account all the same.
+    /// What falls to a client on another shard is left for `take_handoff`.
+    pub fn update_routed(&mut self, tx: Transaction, routed: Routed) -> Result<(), EngineError> {
+        let (id, client, typ) = (tx.tx, tx.client, tx.typ.clone());
+        self.retention.tick(tx.timestamp);
+        self.expire()?;
+
+        let routed = match self.retention.is_evicted(id) {
+            true => Routed {
+                elsewhere: Some(Elsewhere::Evicted),
+                ..routed
+            },
+            false => routed,
+        };
+        let result = self.apply(tx, routed);
+
+        match (&typ, &result) {
+            // a failed withdrawal or transfer is recorded too, to keep its id.
+            (
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer,
+                Ok(()) | Err(EngineError::InsufficientFunds(_)),
+            ) => {
+                self.retention.keep(id, client);
+                self.expire()?;
+            }
+            (TransactionType::Resolve | TransactionType::Chargeback, Ok(()))
+                if !self.map[&client].disputed.contains_key(&id) && self.retention.unpin(id) =>
+            {
+                self.history.remove(id)?;
+                self.retention.evict(id);
             }
-            Some(acct) => {
-                acct.update(tx);
+            _ => (),
+        }
+
+        #[cfg(test)]
+        for acct in self.map.values() {
+            acct.check_held(self.history.as_mut());
+        }
+        result
+    }
+
+    fn apply(&mut self, mut tx: Transaction, routed: Routed) -> Result<(), EngineError> {
+        if let Some(amount) = tx.amount {
+            let rounded = self.config.rounding.round(amount, self.config.max_scale);
+            tx.amount = Some(rounded.ok_or(EngineError::InvalidAmount(tx.tx))?);
+        }
+
+        let acct = self
+            .map
+            .entry(tx.client)
+            .or_insert_with(|| ClientAccount::new(tx.client));
+
+        if acct.locked && !self.config.lock_policy.permits(&tx.typ) {
+            return Err(EngineError::AccountLocked(acct.id));
+        }
+
+        // as the history would say, were the transaction here and still in it. The account's opened all the same.
+        let disputes = matches!(
+            tx.typ,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        let moves = matches!(
+            tx.typ,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        );
+        match routed.elsewhere {
+            Some(Elsewhere::Owned) if disputes => return Err(EngineError::WrongClient(tx.tx)),
+            Some(Elsewhere::Evicted) if disputes => {
+                return Err(EngineError::OutsideDisputeWindow(tx.tx))
             }
+            Some(_) if moves => return Err(EngineError::DuplicateTx(tx.tx)),
+            _ => (),
         }
 
+        if tx.typ == TransactionType::Transfer {
+            return self.transfer(tx, routed.recipient_accepts);
+        }
+
+        // a disputed transfer's funds are held by the client it went to, so its copy of the dispute follows the sender's.
+        let (id, typ) = (tx.tx, tx.typ.clone());
+        let before = match typ {
+            TransactionType::Resolve | TransactionType::Chargeback => acct
+                .disputed
+                .get(&id)
+                .map(|dispute| &dispute.record)
+                .filter(|record| record.to.is_some())
+                .cloned(),
+            _ => None,
+        };
+
+        let acct = self
+            .map
+            .get_mut(&tx.client)
+            .expect("the account was opened above");
+        acct.update(self.history.as_mut(), &self.config, tx)?;
+        acct.transactions += 1;
+
+        let after = match typ {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                acct.disputed
+                    .get(&id)
+                    .map(|dispute| &dispute.record)
+                    .filter(|record| record.to.is_some())
+                    .cloned()
+            }
+            _ => None,
+        };
+        if let Some(record) = after.as_ref().or(before.as_ref()) {
+            let charged_back = match (&typ, &before) {
+                (TransactionType::Chargeback, Some(before)) => {
+                    before.amount - after.as_ref().map_or(Decimal::ZERO, |r| r.amount)
+                }
+                _ => Decimal::ZERO,
+            };
+            let handoff = Handoff {
+                tx: id,
+                to: record.to.expect("only transfers are handed off"),
+                asset: record.asset,
+                amount: -charged_back,
+                credit: false,
+                disputed: after,
+            };
+            // a transfer's recipient stays wherever the transfer left it, so it's only missing if it's on another shard.
+            match self.map.contains_key(&handoff.to) {
+                true => self.receive(handoff),
+                false => self.handoff = Some(handoff),
+            }
+        }
         Ok(())
     }
 
-    // Will write the current state of all accounts to specified Writer.
-    // Will fail and return error if one is encountered.
-    // I chose to not round here as the input is expected to be 4 digit precision -
-    // The conversion to decimal should keep the values as 4 digit decimal precision.
-    pub fn write_csv<T: std::io::Write>(self, writer: T) -> Result<(), Box<dyn Error>> {
-        let mut wtr: Writer<T> = csv::Writer::from_writer(writer);
-        // write header
-        wtr.write_record(&["id", "available", "held", "total", "locked"])?;
-
-        // then write each record
-        for (_, v) in self.map.into_iter() {
-            wtr.serialize(v)?;
+    /// Applies what a transaction on another shard did to a client here, the other end of a transfer.
+    /// Must be applied in the order of the rows around it, eg straight after the transaction.
+    pub fn receive(&mut self, handoff: Handoff) {
+        let acct = self
+            .map
+            .entry(handoff.to)
+            .or_insert_with(|| ClientAccount::new(handoff.to));
+        if !handoff.amount.is_zero() {
+            *acct.balance(handoff.asset) += handoff.amount;
+        }
+        if handoff.credit {
+            acct.transactions += 1;
         }
+        match handoff.disputed {
+            Some(record) => acct.open(handoff.tx, Dispute::received(record)),
+            None => {
+                acct.close(handoff.tx);
+            }
+        }
+    }
 
-        wtr.flush()?;
+    /// Takes what the last transaction left for a client on another shard, if anything.
+    pub fn take_handoff(&mut self) -> Option<Handoff> {
+        self.handoff.take()
+    }
+
+    /// Whether a client's account takes deposits under the `LockPolicy`, eg for a transfer to it from another shard.
+    pub fn accepts(&self, client: u16) -> bool {
+        let permits = self.config.lock_policy.permits(&TransactionType::Deposit);
+        !self
+            .map
+            .get(&client)
+            .is_some_and(|acct| acct.locked && !permits)
+    }
+
+    /// Evicts every transaction that's left the dispute window from the history.
+    /// One with a dispute open is pinned instead, and evicted once the dispute closes.
+    fn expire(&mut self) -> Result<(), EngineError> {
+        for kept in self.retention.expired() {
+            let disputed = self
+                .map
+                .get(&kept.client)
+                .is_some_and(|acct| acct.disputed.contains_key(&kept.tx));
+            if disputed {
+                self.retention.pin(kept);
+            } else {
+                self.history.remove(kept.tx)?;
+                self.retention.evict(kept.tx);
+            }
+        }
         Ok(())
     }
+
+    /// Moves funds from the client to the one named by `to`, all or nothing.
+    /// The sender must have the funds available and neither account can be locked, as far as the `LockPolicy` goes:
+    /// the sender is judged as a transfer and the recipient as a deposit.
+    /// Like a withdrawal, a transfer without the funds still uses up its id.
+    ///
+    /// `recipient_accepts`: for a recipient on another shard, whether it takes deposits. Its credit is handed off.
+    fn transfer(
+        &mut self,
+        tx: Transaction,
+        recipient_accepts: Option<bool>,
+    ) -> Result<(), EngineError> {
+        if self.history.contains(tx.tx)? {
+            return Err(EngineError::DuplicateTx(tx.tx));
+        }
+        let amount = tx.amount.ok_or(EngineError::MissingAmount(tx.tx))?;
+        if amount.is_sign_negative() {
+            return Err(EngineError::InvalidAmount(tx.tx));
+        }
+        let to = match tx.to {
+            Some(to) if to != tx.client => to,
+            _ => return Err(EngineError::InvalidTransfer(tx.tx)),
+        };
+
+        if !recipient_accepts.unwrap_or_else(|| self.accepts(to)) {
+            return Err(EngineError::AccountLocked(to));
+        }
+
+        let sender = self
+            .map
+            .get_mut(&tx.client)
+            .expect("update opens the sender's account");
+        if sender.spendable_of(tx.asset) < amount {
+            self.history.insert(
+                tx.tx,
+                TransactionHistoryRecord {
+                    client: tx.client,
+                    typ: TransactionType::FailedWithdrawal,
+                    amount,
+                    asset: tx.asset,
+                    to: None,
+                    state: DisputeState::Settled,
+                },
+            )?;
+            return Err(EngineError::InsufficientFunds(tx.tx));
+        }
+        *sender.balance(tx.asset) -= amount;
+        sender.transactions += 1;
+
+        let credit = Handoff {
+            tx: tx.tx,
+            to,
+            asset: tx.asset,
+            amount,
+            credit: true,
+            disputed: None,
+        };
+        match recipient_accepts {
+            Some(_) => self.handoff = Some(credit),
+            None => self.receive(credit),
+        }
+
+        self.history.insert(
+            tx.tx,
+            TransactionHistoryRecord {
+                client: tx.client,
+                typ: TransactionType::Transfer,
+                amount,
+                asset: tx.asset,
+                to: Some(to),
+                state: DisputeState::Settled,
+            },
+        )
+    }
+
+    /// Seeds an account from the snapshot of an earlier run, before any transactions are applied.
+    /// Only the total, lock and transaction count come back: held funds come back with their disputes in `restore_record`.
+    /// Restore each of a client's assets in turn, they add to the same account.
+    pub fn restore_account(&mut self, snapshot: &AccountSnapshot) {
+        let acct = self
+            .map
+            .entry(snapshot.client)
+            .or_insert_with(|| ClientAccount::new(snapshot.client));
+        acct.balances.insert(snapshot.asset, snapshot.total);
+        acct.locked = snapshot.locked;
+        acct.transactions = snapshot.transactions;
+    }
+
+    /// Puts a transaction from an earlier run back in the history, reopening its dispute if it had one.
+    /// It's back in the dispute window as if it had just been applied, until `restore_retention` says otherwise.
+    /// The owning account must have been restored first. A disputed transfer's copy of the dispute is reopened
+    /// with `restore_received`, wherever the client it went to is.
+    ///
+    /// `disputed`: how much of the transaction was still disputed, if any.
+    pub fn restore_record(
+        &mut self,
+        tx: u32,
+        mut record: TransactionHistoryRecord,
+        disputed: Option<Decimal>,
+    ) -> Result<(), EngineError> {
+        if let Some(amount) = disputed {
+            // files from before there were dispute states only say it's disputed.
+            record.state = DisputeState::Disputed;
+            let acct = self.map.get_mut(&record.client).ok_or_else(|| {
+                EngineError::InvalidState(format!(
+                    "tx {} is disputed but client {} has no account",
+                    tx, record.client
+                ))
+            })?;
+            let disputed = TransactionHistoryRecord {
+                amount,
+                ..record.clone()
+            };
+            let policy = self.config.withdrawal_disputes;
+            acct.open(tx, Dispute::new(disputed, policy));
+        }
+
+        self.retention.keep(tx, record.client);
+        self.history.insert(tx, record)
+    }
+
+    /// Reopens the copy of a disputed transfer's dispute held by the client it went to, as `restore_record` reopens
+    /// the sender's. Its account can be restored before or after.
+    ///
+    /// `disputed`: how much of the transfer was still disputed.
+    pub fn restore_received(
+        &mut self,
+        tx: u32,
+        record: &TransactionHistoryRecord,
+        disputed: Decimal,
+    ) {
+        if let Some(to) = record.to {
+            let disputed = TransactionHistoryRecord {
+                amount: disputed,
+                state: DisputeState::Disputed,
+                ..record.clone()
+            };
+            self.map
+                .entry(to)
+                .or_insert_with(|| ClientAccount::new(to))
+                .open(tx, Dispute::received(disputed));
+        }
+    }
+
+    /// Puts back the dispute window of an earlier run, once its transactions are restored.
+    ///
+    /// `clock`: the newest timestamp it had seen.
+    /// `evicted`: the ids it had evicted. With several shards every one gets them all, ids are global.
+    /// `pinned`: the transactions it had pinned, only those of this shard's clients.
+    /// `kept`: the transactions it had in the window, only those of this shard's clients.
+    pub fn restore_retention(
+        &mut self,
+        clock: u64,
+        evicted: &IdRanges,
+        pinned: &[(u32, u16)],
+        kept: Vec<Kept>,
+    ) {
+        self.retention.restore(clock, evicted, pinned, kept);
+    }
+
+    /// The dispute window, eg to save it for the next run.
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    /// Starts keeping the ids the `RetentionPolicy` evicts, to be taken with `take_evicted`, eg by a shard router.
+    pub fn report_evicted(&mut self) {
+        self.retention.report();
+    }
+
+    /// Takes the ids evicted since last time, once there are at least `at_least` of them.
+    pub fn take_evicted(&mut self, at_least: usize) -> Option<Vec<u32>> {
+        self.retention.take_evicted(at_least)
+    }
+
+    /// Whether a transaction id has been used here, as it would look to another shard: `Owned` while it's in the history,
+    /// `Evicted` once it's left. None if it hasn't been used.
+    pub fn used(&mut self, tx: u32) -> Result<Option<Elsewhere>, EngineError> {
+        if self.retention.is_evicted(tx) {
+            return Ok(Some(Elsewhere::Evicted));
+        }
+        Ok(self.history.contains(tx)?.then_some(Elsewhere::Owned))
+    }
+
+    /// Visits every transaction in the history along with how much of it is disputed, if any, eg to save it for the next run.
+    pub fn for_each_record(
+        &mut self,
+        mut f: impl FnMut(u32, &TransactionHistoryRecord, Option<Decimal>) -> Result<(), EngineError>,
+    ) -> Result<(), EngineError> {
+        let map = &self.map;
+        self.history.for_each(&mut |tx, record| {
+            let disputed = map
+                .get(&record.client)
+                .and_then(|acct| acct.disputed.get(&tx))
+                .map(|dispute| dispute.record.amount);
+            f(tx, record, disputed)
+        })
+    }
+
+    /// Returns the account for a client, if it has seen any transactions.
+    pub fn get(&self, client: u16) -> Option<&ClientAccount> {
+        self.map.get(&client)
+    }
+
+    /// Iterates over every account in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &ClientAccount> {
+        self.map.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Takes a snapshot of every account and asset without stopping the engine, in client then asset order.
+    pub fn snapshot(&self) -> Vec<AccountSnapshot> {
+        let mut snapshots: Vec<_> = self
+            .map
+            .values()
+            .flat_map(ClientAccount::snapshots)
+            .collect();
+        snapshots.sort_unstable_by_key(|s| (s.client, s.asset));
+        snapshots
+    }
+
+    /// Moves every account from `other` into these accounts.
+    /// Clients are expected to be disjoint, as they are across shards, otherwise `other` wins.
+    /// Only the accounts move, `other`'s history is dropped, so merge once no more transactions are coming.
+    pub fn merge(&mut self, other: ClientAccounts) {
+        self.map.extend(other.map);
+    }
+
+    // Will write the current state of all accounts to specified Writer.
+    // Doesn't consume the accounts so it can be called mid-stream for checkpoints.
+    // Will fail and return error if one is encountered.
+    // I chose to not round here as input amounts are parsed exactly and limited to `max_scale` digits.
+    // Anything restored from elsewhere is rounded with `AccountSnapshot::round` by the caller if it wants.
+    pub fn write_csv<T: std::io::Write>(&self, writer: T) -> Result<(), EngineError> {
+        write_csv(&self.snapshot(), writer)
+    }
 }
 
 #[cfg(test)]
 // Lots of tests here - cover all assumptions and most any behaviour I could think up.
 mod tests {
+    use std::error::Error;
     use std::io::BufWriter;
 
+    use crate::config::{LockPolicy, RedisputePolicy, RetentionPolicy, Rounding};
+
     use super::*;
 
     #[test]
     fn should_be_able_to_create_new_client_account() {
         let acct = ClientAccount::new(1);
+        let history = MemoryHistory::new();
         assert_eq!(acct.id, 1);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(0.0));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(0.0));
         assert!(acct.disputed.is_empty());
-        assert!(acct.transaction_history.is_empty());
+        assert!(history.is_empty());
 
         assert_eq!(acct.available(), dec!(0.0));
         assert_eq!(acct.held(), dec!(0.0));
@@ -224,24 +1089,37 @@ mod tests {
     #[test]
     fn client_account_should_process_deposit_and_store_in_history() {
         let mut acct = ClientAccount::new(2);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.id, 2);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.1111));
         assert!(acct.disputed.is_empty());
-        assert_eq!(acct.transaction_history.len(), 1);
+        assert_eq!(history.len(), 1);
         assert_eq!(
-            acct.transaction_history.get(&0).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(0).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Deposit,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
 
@@ -252,31 +1130,52 @@ mod tests {
     #[test]
     fn client_account_should_process_withdrawal_and_store_in_history() {
         let mut acct = ClientAccount::new(2);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.id, 2);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.0));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.0));
         assert!(acct.disputed.is_empty());
-        assert_eq!(acct.transaction_history.len(), 2);
+        assert_eq!(history.len(), 2);
         assert_eq!(
-            acct.transaction_history.get(&1).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(1).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Withdrawal,
-                amount: 0.1111
+                amount: dec!(0.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
 
@@ -287,35 +1186,56 @@ mod tests {
     #[test]
     fn client_account_should_process_deposit_dispute() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.id, 1);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.1111));
 
         // one record should be the deposit tx
         assert_eq!(acct.disputed.len(), 1);
-        assert!(acct.disputed.contains(&0));
+        assert!(acct.disputed.contains_key(&0));
 
-        assert_eq!(acct.transaction_history.len(), 1);
+        assert_eq!(history.len(), 1);
         assert_eq!(
-            acct.transaction_history.get(&0).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(0).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Deposit,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Disputed,
             }
         );
 
@@ -326,43 +1246,74 @@ mod tests {
     #[test]
     fn client_account_should_fail_to_withdraw_disputed_funds() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // this should be ignored as all funds held
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(dec!(0.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::InsufficientFunds(1))
+        ));
 
         assert_eq!(acct.id, 1);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.1111));
 
         // one record should be the deposit tx
         assert_eq!(acct.disputed.len(), 1);
-        assert!(acct.disputed.contains(&0));
+        assert!(acct.disputed.contains_key(&0));
 
-        assert_eq!(acct.transaction_history.len(), 2); // failed tx should be logged still.
+        assert_eq!(history.len(), 2); // failed tx should be logged still.
         assert_eq!(
-            acct.transaction_history.get(&0).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(0).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Deposit,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Disputed,
             }
         );
 
@@ -373,32 +1324,55 @@ mod tests {
     #[test]
     fn client_account_should_ignore_duplicate_deposits() {
         let mut acct = ClientAccount::new(2);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // This one is entirely ignored
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Deposit,
+                    client: 1,
+                    tx: 0,
+                    amount: Some(dec!(1.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::DuplicateTx(0))
+        ));
 
         assert_eq!(acct.id, 2);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.1111));
         assert!(acct.disputed.is_empty());
-        assert_eq!(acct.transaction_history.len(), 1);
+        assert_eq!(history.len(), 1);
         assert_eq!(
-            acct.transaction_history.get(&0).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(0).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Deposit,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
 
@@ -409,39 +1383,70 @@ mod tests {
     #[test]
     fn client_account_should_ignore_duplicate_withdrawals() {
         let mut acct = ClientAccount::new(2);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // this one is ignored.
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(dec!(0.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::DuplicateTx(1))
+        ));
 
         assert_eq!(acct.id, 2);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.0));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.0));
         assert!(acct.disputed.is_empty());
-        assert_eq!(acct.transaction_history.len(), 2);
+        assert_eq!(history.len(), 2);
         assert_eq!(
-            acct.transaction_history.get(&1).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(1).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Withdrawal,
-                amount: 0.1111
+                amount: dec!(0.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
 
@@ -452,94 +1457,159 @@ mod tests {
     #[test]
     fn client_account_should_process_deposit_resolution_and_withdraw_funds() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
 
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 1);
-        assert!(acct.disputed.contains(&0));
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(acct.disputed.contains_key(&0));
+        assert_eq!(acct.total(), dec!(1.1111));
 
         // this should be invalid.
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(1.1111),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(dec!(1.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::InsufficientFunds(1))
+        ));
 
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
 
-        acct.update(Transaction {
-            typ: TransactionType::Resolve,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Resolve,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
 
         // this should be ignored as it's a duplicate
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(dec!(0.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::DuplicateTx(1))
+        ));
 
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
 
         // this should be processed as unique
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 2,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.id, 1);
-        assert_eq!(acct.locked, false);
-        assert_eq!(acct.total, dec!(1.0));
+        assert!(!acct.locked);
+        assert_eq!(acct.total(), dec!(1.0));
 
         // one record should be the deposit tx
         assert_eq!(acct.disputed.len(), 0);
         assert!(acct.disputed.is_empty());
 
-        assert_eq!(acct.transaction_history.len(), 3); // two are processed, one failed.
+        assert_eq!(history.len(), 3); // two are processed, one failed.
         assert_eq!(
-            acct.transaction_history.get(&0).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(0).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Deposit,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Resolved,
             }
         );
         assert_eq!(
-            acct.transaction_history.get(&1).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(1).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::FailedWithdrawal,
-                amount: 1.1111
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
         assert_eq!(
-            acct.transaction_history.get(&2).unwrap(),
-            &TransactionHistoryRecord {
+            history.get(2).unwrap().unwrap(),
+            TransactionHistoryRecord {
+                client: acct.id,
                 typ: TransactionType::Withdrawal,
-                amount: 0.1111
+                amount: dec!(0.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
             }
         );
 
@@ -547,39 +1617,98 @@ mod tests {
         assert_eq!(acct.held(), dec!(0.0));
     }
 
+    #[test]
+    fn client_accounts_should_not_dispute_failed_withdrawals() -> Result<(), EngineError> {
+        let mut accts = ClientAccounts::new();
+        let tx = |typ, tx, amount: Option<Decimal>| {
+            let builder = Transaction::builder(typ, 1, tx);
+            match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            }
+        };
+
+        accts.update(tx(TransactionType::Deposit, 1, Some(dec!(1))))?;
+        assert!(matches!(
+            accts.update(tx(TransactionType::Withdrawal, 2, Some(dec!(5)))),
+            Err(EngineError::InsufficientFunds(2))
+        ));
+
+        // nothing moved, so there's nothing to charge back and no reason to lock the account.
+        assert!(matches!(
+            accts.update(tx(TransactionType::Dispute, 2, None)),
+            Err(EngineError::NotDisputable(2))
+        ));
+        assert!(matches!(
+            accts.update(tx(TransactionType::Chargeback, 2, None)),
+            Err(EngineError::NotDisputed(2))
+        ));
+        accts.update(tx(TransactionType::Deposit, 3, Some(dec!(1))))?;
+
+        let acct = accts.get(1).unwrap();
+        assert!(!acct.locked());
+        assert_eq!((acct.total(), acct.held()), (dec!(2), dec!(0)));
+        Ok(())
+    }
+
     #[test]
     fn client_account_should_process_deposit_chargeback_if_disputed() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 1);
-        assert!(acct.disputed.contains(&0));
-        assert_eq!(acct.total, dec!(1.1111));
+        assert!(acct.disputed.contains_key(&0));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(0.0));
         assert_eq!(acct.held(), dec!(1.1111));
 
-        acct.update(Transaction {
-            typ: TransactionType::Chargeback,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Chargeback,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(0.0));
+        assert_eq!(acct.total(), dec!(0.0));
         assert_eq!(acct.available(), dec!(0.0));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -587,28 +1716,47 @@ mod tests {
     #[test]
     fn client_account_should_ignore_deposit_chargeback_if_not_disputed() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.0));
 
-        acct.update(Transaction {
-            typ: TransactionType::Chargeback,
-            client: 1,
-            tx: 0,
-            amount: None,
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Chargeback,
+                    client: 1,
+                    tx: 0,
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::NotDisputed(0))
+        ));
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -617,36 +1765,61 @@ mod tests {
     fn client_account_should_process_withdrawal_dispute() {
         // kind of a wierd case but it's managed without holding as an assumption.
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.0));
+        assert_eq!(acct.total(), dec!(1.0));
         assert_eq!(acct.available(), dec!(1.0));
         assert_eq!(acct.held(), dec!(0.0));
 
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // one dispute, but no change in held assets
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.0));
+        assert_eq!(acct.total(), dec!(1.0));
         assert_eq!(acct.available(), dec!(1.0));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -654,38 +1827,71 @@ mod tests {
     #[test]
     fn client_account_should_process_withdrawal_resolution() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Resolve,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // ensure dispute removed
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.0));
+        assert_eq!(acct.total(), dec!(1.0));
         assert_eq!(acct.available(), dec!(1.0));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -693,38 +1899,71 @@ mod tests {
     #[test]
     fn client_account_should_process_withdrawal_chargeback() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Chargeback,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // ensure dispute removed and account debited
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.0));
         assert!(acct.locked);
@@ -733,22 +1972,41 @@ mod tests {
     #[test]
     fn client_account_should_ignore_larger_withdrawal_than_available() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(1.1112),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(dec!(1.1112)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::InsufficientFunds(1))
+        ));
 
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -756,49 +2014,84 @@ mod tests {
     #[test]
     fn client_account_should_ignore_larger_withdrawal_than_available_with_held_funds() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
-
-        assert_eq!(acct.total, dec!(1.2222));
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.2222));
         assert_eq!(acct.held(), dec!(0.0));
 
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         // check dispute applied
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
 
         // try to draw just a bit more
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(1.1112),
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 2,
+                    amount: Some(dec!(1.1112)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::InsufficientFunds(2))
+        ));
 
         // ensure it's just ignored.
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
     }
@@ -806,36 +2099,63 @@ mod tests {
     #[test]
     fn client_account_should_ignore_unknown_disputes() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.0));
+        assert_eq!(acct.total(), dec!(1.0));
         assert_eq!(acct.available(), dec!(1.0));
         assert_eq!(acct.held(), dec!(0.0));
 
         // Reference invalid tx id
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 3,
-            amount: None,
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Dispute,
+                    client: 1,
+                    tx: 3,
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::UnknownTx(3))
+        ));
 
         assert_eq!(acct.disputed.len(), 0);
-        assert_eq!(acct.total, dec!(1.0));
+        assert_eq!(acct.total(), dec!(1.0));
         assert_eq!(acct.available(), dec!(1.0));
         assert_eq!(acct.held(), dec!(0.0));
     }
@@ -843,43 +2163,78 @@ mod tests {
     #[test]
     fn client_account_should_ignore_unknown_resolution() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
 
-        acct.update(Transaction {
-            typ: TransactionType::Resolve,
-            client: 1,
-            tx: 6, // bad tx
-            amount: None,
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Resolve,
+                    client: 1,
+                    tx: 6, // bad tx
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::UnknownTx(6))
+        ));
 
         // ensure dispute is not resolved.
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
     }
@@ -887,148 +2242,1174 @@ mod tests {
     #[test]
     fn client_account_should_ignore_unknown_chargeback() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
 
-        acct.update(Transaction {
-            typ: TransactionType::Chargeback,
-            client: 1,
-            tx: 6, // bad tx
-            amount: None,
-        });
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Chargeback,
+                    client: 1,
+                    tx: 6, // bad tx
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::UnknownTx(6))
+        ));
 
         // ensure dispute is not resolved.
         assert_eq!(acct.disputed.len(), 1);
-        assert_eq!(acct.total, dec!(1.2222));
+        assert_eq!(acct.total(), dec!(1.2222));
         assert_eq!(acct.available(), dec!(1.1111));
         assert_eq!(acct.held(), dec!(0.1111));
     }
 
     #[test]
-    fn client_account_should_calculate_held_with_disputed_deposit_and_withdrawal() {
+    fn client_account_should_reject_rows_that_change_nothing() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 0,
-            amount: Some(1.1111),
-        });
-
-        acct.update(Transaction {
-            typ: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(0.1111),
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Deposit,
+                    client: 1,
+                    tx: 1,
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::MissingAmount(1))
+        ));
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Resolve,
+                    client: 1,
+                    tx: 0,
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::NotDisputed(0))
+        ));
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 0,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Dispute,
+                    client: 1,
+                    tx: 0,
+                    amount: None,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::AlreadyDisputed(0))
+        ));
+        assert!(matches!(
+            acct.update(&mut history, &EngineConfig::default(), Transaction {
+                typ: TransactionType::Unknown("pirates_rock".into()),
+                client: 1,
+                tx: 2,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            }),
+            Err(EngineError::UnknownType(typ)) if typ == "pirates_rock"
+        ));
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(acct.disputed.len(), 1);
+        assert_eq!(acct.total(), dec!(1.1111));
+        assert_eq!(acct.held(), dec!(1.1111));
+    }
 
-        acct.update(Transaction {
-            typ: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(0.1111),
-        });
+    #[test]
+    fn client_account_should_reject_invalid_amounts() {
+        let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
+
+        assert!(matches!(
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Deposit,
+                    client: 1,
+                    tx: 0,
+                    amount: Some(dec!(-1.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                }
+            ),
+            Err(EngineError::InvalidAmount(0))
+        ));
+        assert!(history.is_empty());
+        assert_eq!(acct.total(), dec!(0.0));
+    }
 
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
+    #[test]
+    fn client_account_should_calculate_held_with_disputed_deposit_and_withdrawal() {
+        let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
-        acct.update(Transaction {
-            typ: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
-            amount: None,
-        });
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Withdrawal,
+                client: 1,
+                tx: 2,
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
+
+        acct.update(
+            &mut history,
+            &EngineConfig::default(),
+            Transaction {
+                typ: TransactionType::Dispute,
+                client: 1,
+                tx: 2,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        )
+        .unwrap();
 
         assert_eq!(acct.disputed.len(), 2);
-        assert_eq!(acct.total, dec!(1.1111));
+        assert_eq!(acct.total(), dec!(1.1111));
         assert_eq!(acct.available(), dec!(1.0000));
         // ensure only the disputed deposit is held.
         assert_eq!(acct.held(), dec!(0.1111));
     }
 
+    #[test]
+    fn client_account_should_dispute_withdrawals_per_policy() {
+        // deposits 10 and withdraws 4, then disputes the withdrawal and settles it with `settle`.
+        let run = |policy, settle| {
+            let config = EngineConfig {
+                withdrawal_disputes: policy,
+                ..EngineConfig::default()
+            };
+            let mut acct = ClientAccount::new(1);
+            let mut history = MemoryHistory::new();
+            let mut apply = |acct: &mut ClientAccount, typ, tx, amount: Option<Decimal>| {
+                let builder = Transaction::builder(typ, 1, tx);
+                let tx = match amount {
+                    Some(amount) => builder.amount(amount).build(),
+                    None => builder.build(),
+                };
+                acct.update(&mut history, &config, tx)
+            };
+
+            apply(&mut acct, TransactionType::Deposit, 1, Some(dec!(10))).unwrap();
+            apply(&mut acct, TransactionType::Withdrawal, 2, Some(dec!(4))).unwrap();
+            let disputed = apply(&mut acct, TransactionType::Dispute, 2, None)
+                .map(|_| (acct.total(), acct.held(), acct.available()));
+            let settled = apply(&mut acct, settle, 2, None)
+                .map(|_| (acct.total(), acct.held(), acct.available(), acct.locked));
+            (disputed, settled)
+        };
+
+        let (disputed, settled) = run(WithdrawalDisputePolicy::Ignore, TransactionType::Chargeback);
+        assert_eq!(disputed.unwrap(), (dec!(6), dec!(0), dec!(6)));
+        assert_eq!(settled.unwrap(), (dec!(6), dec!(0), dec!(6), true));
+
+        let (disputed, settled) = run(
+            WithdrawalDisputePolicy::CreditOnChargeback,
+            TransactionType::Chargeback,
+        );
+        assert_eq!(disputed.unwrap(), (dec!(6), dec!(0), dec!(6)));
+        assert_eq!(settled.unwrap(), (dec!(10), dec!(0), dec!(10), true));
+
+        let (disputed, settled) = run(WithdrawalDisputePolicy::Reject, TransactionType::Chargeback);
+        assert!(matches!(disputed, Err(EngineError::WithdrawalDispute(2))));
+        assert!(matches!(settled, Err(EngineError::NotDisputed(2))));
+
+        // held as a negative the funds are available while disputed, and stay so once charged back.
+        let (disputed, settled) = run(
+            WithdrawalDisputePolicy::HoldNegative,
+            TransactionType::Chargeback,
+        );
+        assert_eq!(disputed.unwrap(), (dec!(6), dec!(-4), dec!(10)));
+        assert_eq!(settled.unwrap(), (dec!(10), dec!(0), dec!(10), true));
+
+        // but a resolve takes them away again.
+        let (_, settled) = run(
+            WithdrawalDisputePolicy::HoldNegative,
+            TransactionType::Resolve,
+        );
+        assert_eq!(settled.unwrap(), (dec!(6), dec!(0), dec!(6), false));
+    }
+
+    #[test]
+    fn client_accounts_should_not_spend_what_a_disputed_withdrawal_released(
+    ) -> Result<(), EngineError> {
+        let mut accts = ClientAccounts::with_config(EngineConfig {
+            withdrawal_disputes: WithdrawalDisputePolicy::HoldNegative,
+            ..Default::default()
+        });
+        let apply = |accts: &mut ClientAccounts, typ, tx, amount: Option<Decimal>| {
+            let builder = Transaction::builder(typ, 1, tx);
+            accts.update(match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            })
+        };
+
+        apply(&mut accts, TransactionType::Deposit, 1, Some(dec!(10)))?;
+        apply(&mut accts, TransactionType::Withdrawal, 2, Some(dec!(10)))?;
+        apply(&mut accts, TransactionType::Dispute, 2, None)?;
+        // it shows as available, but a resolve would take it back.
+        assert!(matches!(
+            apply(&mut accts, TransactionType::Withdrawal, 3, Some(dec!(10))),
+            Err(EngineError::InsufficientFunds(3))
+        ));
+        apply(&mut accts, TransactionType::Resolve, 2, None)?;
+
+        let acct = accts.get(1).unwrap();
+        assert_eq!(acct.available() + acct.held(), acct.total());
+        assert_eq!(acct.total(), dec!(0));
+
+        // once charged back it's the client's to spend.
+        apply(&mut accts, TransactionType::Dispute, 2, None)?;
+        apply(&mut accts, TransactionType::Chargeback, 2, None)?;
+        apply(&mut accts, TransactionType::Unlock, 4, None)?;
+        apply(&mut accts, TransactionType::Withdrawal, 5, Some(dec!(10)))?;
+        let acct = accts.get(1).unwrap();
+        assert_eq!(
+            (acct.available(), acct.held(), acct.total()),
+            (dec!(0), dec!(0), dec!(0))
+        );
+        Ok(())
+    }
+
     #[test]
     // This test detects any kind of imprecision accumulation -
     // I had to switch the impl to use Decimal to make this pass!
     // f32 even while rounding off imprecision along the way gets some drift after a couple k.
-    // Decimal is proven here. We can store f32 in the tx history but we need Decimal in the client.
+    // Decimal is proven here. Amounts are now parsed straight into Decimal so it holds by construction.
     fn client_account_should_not_loose_precision_on_many_updates() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
         for tx in 0..100000 {
-            acct.update(Transaction {
-                typ: TransactionType::Deposit,
-                client: 1,
-                tx,
-                amount: Some(0.1111),
-            });
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(dec!(0.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                },
+            )
+            .unwrap();
         }
 
-        assert_eq!(acct.total, dec!(11110.0))
+        assert_eq!(acct.total(), dec!(11110.0))
     }
 
     #[test]
     // This test detects any kind of imprecision accumulation -
     // I had to switch the impl to use Decimal to make this pass!
     // f32 even while rounding off imprecision along the way gets some drift after a couple k.
-    // Decimal is proven here. We can store f32 in the tx history but we need Decimal in the client.
+    // Decimal is proven here. Amounts are now parsed straight into Decimal so it holds by construction.
     fn client_account_should_not_loose_precision_on_many_disputes() {
         let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
 
         // add a slew of additions
         for tx in 0..100000 {
-            acct.update(Transaction {
-                typ: TransactionType::Deposit,
-                client: 1,
-                tx,
-                amount: Some(0.1111),
-            });
+            acct.update(
+                &mut history,
+                &EngineConfig::default(),
+                Transaction {
+                    typ: TransactionType::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(dec!(0.1111)),
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                },
+            )
+            .unwrap();
         }
 
-        assert_eq!(acct.total, dec!(11110.0));
+        assert_eq!(acct.total(), dec!(11110.0));
         assert_eq!(acct.available(), dec!(11110.0));
         assert_eq!(acct.held(), dec!(0.0));
 
-        // check that we can maintain precision while holding history w/ f32 instead of 129bit Decimal
+        // check that we can maintain precision over withdrawals as well
         for tx in 100000..150000 {
-            acct.update(Transaction {
+            assert!(matches!(
+                acct.update(
+                    &mut history,
+                    &EngineConfig::default(),
+                    Transaction {
+                        typ: TransactionType::Deposit,
+                        client: 1,
+                        tx: tx - 100000,
+                        amount: Some(dec!(0.1111)),
+                        asset: Asset::default(),
+                        to: None,
+                        timestamp: None,
+                    }
+                ),
+                Err(EngineError::DuplicateTx(_))
+            ));
+        }
+
+        assert_eq!(acct.total(), dec!(11110.0));
+        assert_eq!(acct.available(), dec!(11110.0));
+        assert_eq!(acct.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn client_accounts_should_reject_amounts_finer_than_max_scale() {
+        let mut accts = ClientAccounts::with_config(EngineConfig {
+            max_scale: 2,
+            ..Default::default()
+        });
+
+        accts
+            .update(Transaction {
+                typ: TransactionType::Deposit,
+                client: 1,
+                tx: 0,
+                amount: Some(dec!(1.1000)), // trailing zeros don't count.,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            })
+            .unwrap();
+
+        assert!(matches!(
+            accts.update(Transaction {
                 typ: TransactionType::Deposit,
                 client: 1,
-                tx: tx - 100000,
-                amount: Some(0.1111),
+                tx: 1,
+                amount: Some(dec!(1.111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            }),
+            Err(EngineError::InvalidAmount(1))
+        ));
+
+        assert_eq!(accts.map.get(&1).unwrap().total(), dec!(1.1));
+    }
+
+    #[test]
+    fn client_accounts_should_round_amounts_finer_than_max_scale() {
+        let mut accts = ClientAccounts::with_config(EngineConfig {
+            max_scale: 2,
+            rounding: Rounding::HalfUp,
+            ..Default::default()
+        });
+
+        for (typ, tx, amount) in [
+            (TransactionType::Deposit, 0, dec!(1.115)),
+            (TransactionType::Withdrawal, 1, dec!(0.004)),
+            (TransactionType::Withdrawal, 2, dec!(1.124)),
+        ] {
+            accts
+                .update(Transaction::builder(typ, 1, tx).amount(amount).build())
+                .unwrap();
+        }
+
+        // the deposit rounds up to 1.12, 0.004 rounds to nothing and the last withdrawal down to what's left.
+        assert_eq!(accts.map.get(&1).unwrap().total(), dec!(0));
+        assert_eq!(accts.history.get(0).unwrap().unwrap().amount, dec!(1.12));
+
+        // written out at the scale, however many digits came in.
+        accts
+            .update(
+                Transaction::builder(TransactionType::Deposit, 2, 3)
+                    .amount(dec!(1.50000000))
+                    .build(),
+            )
+            .unwrap();
+        let mut snapshots = accts.snapshot();
+        snapshots.iter_mut().for_each(|s| s.round(&accts.config));
+        let mut out = Vec::new();
+        write_csv(&snapshots, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,available,held,total,locked\n1,0.00,0.00,0.00,false\n2,1.50,0.00,1.50,false\n"
+        );
+    }
+
+    #[test]
+    fn client_accounts_should_keep_assets_apart() -> Result<(), EngineError> {
+        let btc: Asset = "BTC".parse().unwrap();
+        let mut accts = ClientAccounts::new();
+        let tx = |typ, tx, amount, asset| {
+            Transaction::builder(typ, 1, tx)
+                .amount(amount)
+                .asset(asset)
+                .build()
+        };
+
+        accts.update(tx(TransactionType::Deposit, 0, dec!(5), Asset::default()))?;
+        accts.update(tx(TransactionType::Deposit, 1, dec!(2), btc))?;
+        // only 2 btc is there to withdraw, whatever else the client holds.
+        assert!(matches!(
+            accts.update(tx(TransactionType::Withdrawal, 2, dec!(3), btc)),
+            Err(EngineError::InsufficientFunds(2))
+        ));
+
+        // the dispute holds btc, whatever asset its own row names.
+        accts.update(
+            Transaction::builder(TransactionType::Dispute, 1, 1)
+                .asset(Asset::default())
+                .build(),
+        )?;
+        let acct = accts.get(1).unwrap();
+        assert_eq!(
+            (acct.held_of(btc), acct.available_of(btc)),
+            (dec!(2), dec!(0))
+        );
+        assert_eq!((acct.held(), acct.available()), (dec!(0), dec!(5)));
+
+        accts.update(Transaction::builder(TransactionType::Chargeback, 1, 1).build())?;
+        let snapshots = accts.snapshot();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(
+            (snapshots[0].asset, snapshots[0].total),
+            (Asset::default(), dec!(5))
+        );
+        assert_eq!((snapshots[1].asset, snapshots[1].total), (btc, dec!(0)));
+        assert!(snapshots.iter().all(|s| s.locked));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_account_should_dispute_part_of_a_transaction() {
+        let mut acct = ClientAccount::new(1);
+        let mut history = MemoryHistory::new();
+        let tx = |typ, amount: Option<Decimal>| {
+            let builder = Transaction::builder(typ, 1, 0);
+            match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            }
+        };
+        let mut apply = |acct: &mut ClientAccount, typ, amount| {
+            acct.update(&mut history, &EngineConfig::default(), tx(typ, amount))
+        };
+
+        apply(&mut acct, TransactionType::Deposit, Some(dec!(10))).unwrap();
+        assert!(matches!(
+            apply(&mut acct, TransactionType::Dispute, Some(dec!(11))),
+            Err(EngineError::InvalidAmount(0))
+        ));
+        apply(&mut acct, TransactionType::Dispute, Some(dec!(4))).unwrap();
+        // more than is disputed can't be released.
+        assert!(matches!(
+            apply(&mut acct, TransactionType::Resolve, Some(dec!(5))),
+            Err(EngineError::InvalidAmount(0))
+        ));
+        apply(&mut acct, TransactionType::Resolve, Some(dec!(1))).unwrap();
+        apply(&mut acct, TransactionType::Chargeback, Some(dec!(2))).unwrap();
+
+        assert_eq!(
+            (acct.total(), acct.held(), acct.available()),
+            (dec!(8), dec!(1), dec!(7))
+        );
+        assert!(acct.locked);
+
+        // a chargeback without an amount reverses whatever is still disputed.
+        apply(&mut acct, TransactionType::Chargeback, None).unwrap();
+        assert_eq!((acct.total(), acct.held()), (dec!(7), dec!(0)));
+        assert!(acct.disputed.is_empty());
+    }
+
+    #[test]
+    fn client_accounts_should_reject_disputes_outside_the_window() {
+        let accounts = |retention| {
+            ClientAccounts::with_config(EngineConfig {
+                retention,
+                ..Default::default()
+            })
+        };
+        let tx = |typ, client, tx, timestamp: Option<u64>| {
+            let builder = Transaction::builder(typ, client, tx).amount(dec!(1));
+            match timestamp {
+                Some(timestamp) => builder.timestamp(timestamp).build(),
+                None => builder.build(),
+            }
+        };
+        let dispute = |tx| Transaction::builder(TransactionType::Dispute, 1, tx).build();
+
+        // the last two to arrive are in the window, whatever their ids.
+        let mut accts = accounts(RetentionPolicy::Transactions(2));
+        for id in [10, 4_000_000_000, 11] {
+            accts
+                .update(tx(TransactionType::Deposit, 1, id, None))
+                .unwrap();
+        }
+        assert!(!accts.history.contains(10).unwrap());
+        assert!(matches!(
+            accts.update(dispute(10)),
+            Err(EngineError::OutsideDisputeWindow(10))
+        ));
+        // a duplicate of an evicted id still opens its account, as one still in the history would.
+        assert!(matches!(
+            accts.update(tx(TransactionType::Deposit, 2, 10, None)),
+            Err(EngineError::DuplicateTx(10))
+        ));
+        assert!(accts.map.contains_key(&2));
+        accts.update(dispute(11)).unwrap();
+
+        // a failed withdrawal takes a place in the window too.
+        let mut accts = accounts(RetentionPolicy::PerClient(1));
+        accts
+            .update(tx(TransactionType::Deposit, 1, 1, None))
+            .unwrap();
+        accts
+            .update(tx(TransactionType::Deposit, 2, 2, None))
+            .unwrap();
+        accts
+            .update(tx(TransactionType::Withdrawal, 1, 3, None))
+            .unwrap();
+        assert!(matches!(
+            accts.update(tx(TransactionType::Withdrawal, 1, 4, None)),
+            Err(EngineError::InsufficientFunds(4))
+        ));
+        for id in [1, 3] {
+            assert!(matches!(
+                accts.update(dispute(id)),
+                Err(EngineError::OutsideDisputeWindow(_))
+            ));
+        }
+        accts
+            .update(Transaction::builder(TransactionType::Dispute, 2, 2).build())
+            .unwrap();
+
+        // a minute from the newest timestamp, which rows without one don't move.
+        let mut accts = accounts(RetentionPolicy::Age(60));
+        accts
+            .update(tx(TransactionType::Deposit, 1, 1, Some(1000)))
+            .unwrap();
+        accts
+            .update(tx(TransactionType::Deposit, 1, 2, Some(1050)))
+            .unwrap();
+        accts
+            .update(tx(TransactionType::Deposit, 1, 3, None))
+            .unwrap();
+        assert!(matches!(
+            accts.update(
+                Transaction::builder(TransactionType::Dispute, 1, 1)
+                    .timestamp(1061)
+                    .build()
+            ),
+            Err(EngineError::OutsideDisputeWindow(1))
+        ));
+        accts.update(dispute(2)).unwrap();
+        accts.update(dispute(3)).unwrap();
+        assert_eq!(accts.get(1).unwrap().held(), dec!(2));
+    }
+
+    #[test]
+    fn client_accounts_should_keep_held_through_many_disputes() {
+        // `update` checks held against the open disputes after every transaction in tests.
+        let mut accts = ClientAccounts::with_config(EngineConfig {
+            withdrawal_disputes: WithdrawalDisputePolicy::HoldNegative,
+            ..Default::default()
+        });
+        let mut apply = |typ, tx, amount: Option<Decimal>| {
+            let builder = Transaction::builder(typ, 1, tx);
+            accts.update(match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            })
+        };
+
+        for tx in 0..200 {
+            apply(TransactionType::Deposit, tx, Some(dec!(1.5))).unwrap();
+        }
+        apply(TransactionType::Withdrawal, 200, Some(dec!(50))).unwrap();
+        for tx in 0..=200 {
+            apply(TransactionType::Dispute, tx, None).unwrap();
+        }
+        for tx in 0..100 {
+            let typ = match tx % 3 {
+                0 => TransactionType::Resolve,
+                1 => TransactionType::Chargeback,
+                _ => TransactionType::Dispute, // already disputed, changes nothing.
+            };
+            let _ = apply(typ, tx, Some(dec!(0.5)));
+        }
+        apply(TransactionType::Chargeback, 200, Some(dec!(20))).unwrap();
+
+        // 200 deposits, 34 with 0.5 resolved and 33 with 0.5 charged back, less what's left of the withdrawal.
+        let acct = accts.get(1).unwrap();
+        assert_eq!(acct.held(), dec!(300) - dec!(17) - dec!(16.5) - dec!(30));
+        assert_eq!(acct.total(), dec!(300) - dec!(50) - dec!(16.5) + dec!(20));
+        assert_eq!(acct.snapshots()[0].open_disputes, 201);
+    }
+
+    #[test]
+    fn client_accounts_should_only_dispute_again_as_the_policy_allows() {
+        let accounts = |redispute| {
+            let mut accts = ClientAccounts::with_config(EngineConfig {
+                redispute,
+                ..Default::default()
             });
+            for tx in [0, 1] {
+                accts
+                    .update(
+                        Transaction::builder(TransactionType::Deposit, 1, tx)
+                            .amount(dec!(10))
+                            .build(),
+                    )
+                    .unwrap();
+            }
+            accts
+        };
+        let apply = |accts: &mut ClientAccounts, typ, tx, amount: Option<Decimal>| {
+            let builder = Transaction::builder(typ, 1, tx);
+            accts.update(match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            })
+        };
+
+        let mut accts = accounts(RedisputePolicy::AfterResolve);
+        apply(&mut accts, TransactionType::Dispute, 0, None).unwrap();
+        apply(&mut accts, TransactionType::Resolve, 0, None).unwrap();
+        assert!(matches!(
+            apply(&mut accts, TransactionType::Resolve, 0, None),
+            Err(EngineError::DisputeClosed(0, DisputeState::Resolved))
+        ));
+        apply(&mut accts, TransactionType::Dispute, 0, None).unwrap();
+        apply(&mut accts, TransactionType::Chargeback, 0, None).unwrap();
+        assert!(matches!(
+            apply(&mut accts, TransactionType::Dispute, 0, None),
+            Err(EngineError::DisputeClosed(0, DisputeState::ChargedBack))
+        ));
+
+        let mut accts = accounts(RedisputePolicy::Never);
+        apply(&mut accts, TransactionType::Dispute, 0, None).unwrap();
+        apply(&mut accts, TransactionType::Resolve, 0, None).unwrap();
+        assert!(matches!(
+            apply(&mut accts, TransactionType::Dispute, 0, None),
+            Err(EngineError::DisputeClosed(0, DisputeState::Resolved))
+        ));
+
+        // charged back again, but only ever what's left of it.
+        let mut accts = accounts(RedisputePolicy::Always);
+        apply(&mut accts, TransactionType::Dispute, 1, None).unwrap();
+        apply(&mut accts, TransactionType::Chargeback, 1, Some(dec!(4))).unwrap();
+        apply(&mut accts, TransactionType::Resolve, 1, None).unwrap();
+        apply(&mut accts, TransactionType::Dispute, 1, None).unwrap();
+        assert_eq!(accts.get(1).unwrap().held(), dec!(6));
+        apply(&mut accts, TransactionType::Chargeback, 1, None).unwrap();
+        assert!(matches!(
+            apply(&mut accts, TransactionType::Dispute, 1, None),
+            Err(EngineError::DisputeClosed(1, DisputeState::ChargedBack))
+        ));
+        assert_eq!(accts.get(1).unwrap().total(), dec!(10));
+        assert_eq!(accts.history.get(1).unwrap().unwrap().amount, dec!(0));
+    }
+
+    #[test]
+    fn client_accounts_should_transfer_between_clients() -> Result<(), EngineError> {
+        let mut accts = ClientAccounts::new();
+        let transfer = |tx, amount, to| {
+            Transaction::builder(TransactionType::Transfer, 1, tx)
+                .amount(amount)
+                .to(to)
+                .build()
+        };
+
+        accts.update(
+            Transaction::builder(TransactionType::Deposit, 1, 0)
+                .amount(dec!(5))
+                .build(),
+        )?;
+        accts.update(transfer(1, dec!(3), 2))?;
+        assert!(matches!(
+            accts.update(transfer(2, dec!(3), 2)),
+            Err(EngineError::InsufficientFunds(2))
+        ));
+        assert!(matches!(
+            accts.update(transfer(3, dec!(1), 1)),
+            Err(EngineError::InvalidTransfer(3))
+        ));
+        assert_eq!(accts.get(1).unwrap().total(), dec!(2));
+        assert_eq!(accts.get(2).unwrap().total(), dec!(3));
+
+        // the sender disputes it, and the funds are held where they went.
+        accts.update(Transaction::builder(TransactionType::Dispute, 1, 1).build())?;
+        assert!(matches!(
+            accts.update(Transaction::builder(TransactionType::Resolve, 2, 1).build()),
+            Err(EngineError::WrongClient(1))
+        ));
+        let (sender, recipient) = (accts.get(1).unwrap(), accts.get(2).unwrap());
+        assert_eq!((sender.held(), sender.available()), (dec!(0), dec!(2)));
+        assert_eq!(
+            (recipient.held(), recipient.available()),
+            (dec!(3), dec!(0))
+        );
+
+        // a chargeback moves them back and locks the sender.
+        accts.update(Transaction::builder(TransactionType::Chargeback, 1, 1).build())?;
+        let (sender, recipient) = (accts.get(1).unwrap(), accts.get(2).unwrap());
+        assert_eq!((sender.total(), sender.locked()), (dec!(5), true));
+        assert_eq!((recipient.total(), recipient.held()), (dec!(0), dec!(0)));
+        assert!(matches!(
+            accts.update(transfer(4, dec!(1), 2)),
+            Err(EngineError::AccountLocked(1))
+        ));
+
+        Ok(())
+    }
+
+    fn locked_accounts(lock_policy: LockPolicy) -> ClientAccounts {
+        let mut accts = ClientAccounts::with_config(EngineConfig {
+            lock_policy,
+            ..Default::default()
+        });
+
+        for (typ, tx, amount) in [
+            (TransactionType::Deposit, 0, Some(dec!(2.0))),
+            (TransactionType::Deposit, 1, Some(dec!(1.0))),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Dispute, 0, None),
+            (TransactionType::Chargeback, 0, None),
+        ] {
+            accts
+                .update(Transaction {
+                    typ,
+                    client: 1,
+                    tx,
+                    amount,
+                    asset: Asset::default(),
+                    to: None,
+                    timestamp: None,
+                })
+                .unwrap();
         }
 
-        assert_eq!(acct.total, dec!(11110.0));
-        assert_eq!(acct.available(), dec!(11110.0));
-        assert_eq!(acct.held(), dec!(0.0));
+        assert!(accts.map.get(&1).unwrap().locked);
+        accts
+    }
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction {
+            typ: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Some(dec!(1.0)),
+            asset: Asset::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
+
+    fn withdrawal(tx: u32) -> Transaction {
+        Transaction {
+            typ: TransactionType::Withdrawal,
+            client: 1,
+            tx,
+            amount: Some(dec!(0.5)),
+            asset: Asset::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
+
+    fn resolve(tx: u32) -> Transaction {
+        Transaction {
+            typ: TransactionType::Resolve,
+            client: 1,
+            tx,
+            amount: None,
+            asset: Asset::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn client_accounts_should_reject_everything_when_locked_with_reject_all() {
+        let mut accts = locked_accounts(LockPolicy::RejectAll);
+
+        assert!(matches!(
+            accts.update(deposit(2)),
+            Err(EngineError::AccountLocked(1))
+        ));
+        assert!(matches!(
+            accts.update(withdrawal(3)),
+            Err(EngineError::AccountLocked(1))
+        ));
+        assert!(matches!(
+            accts.update(resolve(1)),
+            Err(EngineError::AccountLocked(1))
+        ));
+
+        let acct = accts.map.get(&1).unwrap();
+        assert_eq!(acct.total(), dec!(1.0));
+        assert_eq!(acct.held(), dec!(1.0));
+    }
+
+    #[test]
+    fn client_accounts_should_reject_only_withdrawals_when_locked_with_reject_withdrawals() {
+        let mut accts = locked_accounts(LockPolicy::RejectWithdrawals);
+
+        accts.update(deposit(2)).unwrap();
+        accts.update(resolve(1)).unwrap();
+        assert!(matches!(
+            accts.update(withdrawal(3)),
+            Err(EngineError::AccountLocked(1))
+        ));
+
+        let acct = accts.map.get(&1).unwrap();
+        assert_eq!(acct.total(), dec!(2.0));
+        assert_eq!(acct.available(), dec!(2.0));
+    }
+
+    #[test]
+    fn client_accounts_should_continue_disputes_when_locked_with_allow_disputes() {
+        let mut accts = locked_accounts(LockPolicy::AllowDisputes);
+
+        assert!(matches!(
+            accts.update(deposit(2)),
+            Err(EngineError::AccountLocked(1))
+        ));
+        assert!(matches!(
+            accts.update(withdrawal(3)),
+            Err(EngineError::AccountLocked(1))
+        ));
+        accts.update(resolve(1)).unwrap();
+
+        let acct = accts.map.get(&1).unwrap();
+        assert_eq!(acct.total(), dec!(1.0));
+        assert_eq!(acct.available(), dec!(1.0));
+    }
+
+    #[test]
+    fn client_accounts_should_accept_transactions_after_unlock() {
+        let mut accts = locked_accounts(LockPolicy::RejectAll);
+        let unlock = |tx| Transaction {
+            typ: TransactionType::Unlock,
+            client: 1,
+            tx,
+            amount: None,
+            asset: Asset::default(),
+            to: None,
+            timestamp: None,
+        };
+
+        accts.update(unlock(10)).unwrap();
+        assert!(!accts.map.get(&1).unwrap().locked);
+        assert!(matches!(
+            accts.update(unlock(11)),
+            Err(EngineError::NotLocked(1))
+        ));
+
+        accts.update(deposit(2)).unwrap();
+        accts.update(withdrawal(3)).unwrap();
+
+        let acct = accts.map.get(&1).unwrap();
+        assert_eq!(acct.total(), dec!(1.5));
+        assert_eq!(acct.available(), dec!(0.5));
+    }
+
+    #[test]
+    fn client_accounts_should_expose_account_snapshots() {
+        let mut accts = ClientAccounts::new();
+        assert!(accts.is_empty());
+
+        for tx in [
+            Transaction::builder(TransactionType::Deposit, 1, 0)
+                .amount(dec!(2.0))
+                .build(),
+            Transaction::builder(TransactionType::Deposit, 2, 1)
+                .amount(dec!(1.5))
+                .build(),
+            Transaction::builder(TransactionType::Dispute, 2, 1).build(),
+        ] {
+            accts.update(tx).unwrap();
+        }
+
+        assert_eq!(accts.len(), 2);
+        assert!(accts.get(3).is_none());
+
+        let acct = accts.get(2).unwrap();
+        assert_eq!(acct.id(), 2);
+        assert_eq!(acct.available(), dec!(0.0));
+        assert_eq!(acct.held(), dec!(1.5));
+        assert_eq!(acct.total(), dec!(1.5));
+        assert!(!acct.locked());
+
+        let mut ids: Vec<u16> = accts.iter().map(|a| a.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn client_accounts_should_snapshot_and_write_mid_stream() -> Result<(), Box<dyn Error>> {
+        let mut accts = ClientAccounts::new();
+
+        accts.update(
+            Transaction::builder(TransactionType::Deposit, 1, 0)
+                .amount(dec!(1.5))
+                .build(),
+        )?;
+
+        let mut first = BufWriter::new(Vec::new());
+        accts.write_csv(&mut first)?;
+        assert_eq!(
+            accts.snapshot(),
+            vec![AccountSnapshot {
+                client: 1,
+                asset: Asset::default(),
+                available: dec!(1.5),
+                held: dec!(0),
+                total: dec!(1.5),
+                locked: false,
+                open_disputes: 0,
+                transactions: 1,
+            }]
+        );
+
+        // the engine keeps running after a snapshot.
+        accts.update(
+            Transaction::builder(TransactionType::Withdrawal, 1, 1)
+                .amount(dec!(0.5))
+                .build(),
+        )?;
+
+        let mut second = BufWriter::new(Vec::new());
+        accts.write_csv(&mut second)?;
+
+        assert_eq!(
+            String::from_utf8(first.into_inner()?)?,
+            "id,available,held,total,locked\n1,1.5000,0.0,1.5000,false\n"
+        );
+        assert_eq!(
+            String::from_utf8(second.into_inner()?)?,
+            "id,available,held,total,locked\n1,1.0000,0.0,1.0000,false\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_accounts_should_keep_tx_ids_unique_across_clients() {
+        let mut accts = ClientAccounts::new();
+        let tx = |typ: TransactionType, client: u16, tx: u32| {
+            Transaction::builder(typ, client, tx)
+                .amount(dec!(1.0))
+                .build()
+        };
+
+        accts.update(deposit(7)).unwrap();
+        assert!(matches!(
+            accts.update(tx(TransactionType::Deposit, 2, 7)),
+            Err(EngineError::DuplicateTx(7))
+        ));
+        assert!(matches!(
+            accts.update(tx(TransactionType::Withdrawal, 2, 7)),
+            Err(EngineError::DuplicateTx(7))
+        ));
+
+        // only the owner can dispute, resolve or charge back its transaction.
+        assert!(matches!(
+            accts.update(tx(TransactionType::Dispute, 2, 7)),
+            Err(EngineError::WrongClient(7))
+        ));
+        accts.update(tx(TransactionType::Dispute, 1, 7)).unwrap();
+        assert!(matches!(
+            accts.update(tx(TransactionType::Resolve, 2, 7)),
+            Err(EngineError::WrongClient(7))
+        ));
+        assert!(matches!(
+            accts.update(tx(TransactionType::Chargeback, 2, 7)),
+            Err(EngineError::WrongClient(7))
+        ));
+
+        assert_eq!(accts.get(1).unwrap().held(), dec!(1.0));
+        assert_eq!(accts.get(2).unwrap().total(), dec!(0.0));
+    }
+
+    #[test]
+    fn client_accounts_should_merge_disjoint_accounts() {
+        let mut left = ClientAccounts::new();
+        let mut right = ClientAccounts::new();
+
+        left.update(deposit(0)).unwrap();
+        right
+            .update(
+                Transaction::builder(TransactionType::Deposit, 2, 1)
+                    .amount(dec!(2.0))
+                    .build(),
+            )
+            .unwrap();
+
+        left.merge(right);
+
+        assert_eq!(left.len(), 2);
+        assert_eq!(left.get(1).unwrap().total(), dec!(1.0));
+        assert_eq!(left.get(2).unwrap().total(), dec!(2.0));
     }
 
     #[test]
@@ -1040,17 +3421,23 @@ mod tests {
                 typ: TransactionType::Deposit,
                 client: 1,
                 tx,
-                amount: Some(0.1111),
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
             })?;
         }
 
-        // check that we can maintain precision while holding history w/ f32 instead of 129bit Decimal
+        // check that we can maintain precision over withdrawals as well
         for tx in 100000..150000 {
             let _ = &accts.update(Transaction {
                 typ: TransactionType::Withdrawal,
                 client: 1,
                 tx,
-                amount: Some(0.1111),
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
             })?;
         }
 
@@ -1079,17 +3466,23 @@ mod tests {
                 typ: TransactionType::Deposit,
                 client: 1,
                 tx,
-                amount: Some(0.1111),
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
             })?;
         }
 
-        // check that we can maintain precision while holding history w/ f32 instead of 129bit Decimal
+        // check that we can maintain precision over withdrawals as well
         for tx in 100000..150000 {
             let _ = &accts.update(Transaction {
                 typ: TransactionType::Withdrawal,
                 client: 1,
                 tx,
-                amount: Some(0.1111),
+                amount: Some(dec!(0.1111)),
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
             })?;
         }
 
@@ -1098,6 +3491,9 @@ mod tests {
             client: 1,
             tx: 4,
             amount: None,
+            asset: Asset::default(),
+            to: None,
+            timestamp: None,
         })?;
 
         let mut buf = BufWriter::new(Vec::new());
@@ -0,0 +1,479 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::transaction::{DisputeState, TransactionType};
+
+/// Settings the engine is started with.
+///
+/// `max_scale`: the most fractional digits an amount may have, on input and when written out.
+/// `rounding`: what happens to an amount with more digits than that.
+/// `lock_policy`: what a locked account may still do.
+/// `redispute`: whether a transaction can be disputed again once its dispute is closed.
+/// `withdrawal_disputes`: what disputing a withdrawal does.
+/// `retention`: how long transactions can be disputed, and so how long their history is kept.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub max_scale: u32,
+    pub rounding: Rounding,
+    pub lock_policy: LockPolicy,
+    pub redispute: RedisputePolicy,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    pub retention: RetentionPolicy,
+}
+
+impl EngineConfig {
+    /// Rounds an amount being written out. Nothing can be rejected there, so `Reject` rounds half to even.
+    /// Only restored state can need it then, as every applied amount was already within `max_scale`.
+    pub fn round_output(&self, amount: Decimal) -> Decimal {
+        let rounding = match self.rounding {
+            Rounding::Reject => Rounding::Bankers,
+            rounding => rounding,
+        };
+        rounding.round(amount, self.max_scale).unwrap_or(amount)
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_scale: 4,
+            rounding: Rounding::Reject,
+            lock_policy: LockPolicy::AllowDisputes,
+            redispute: RedisputePolicy::AfterResolve,
+            withdrawal_disputes: WithdrawalDisputePolicy::CreditOnChargeback,
+            retention: RetentionPolicy::Forever,
+        }
+    }
+}
+
+/// Decides what happens to an amount with more fractional digits than `max_scale`.
+///
+/// `Reject`: the transaction is rejected as an invalid amount.
+/// `Bankers`: rounded to the nearest, halves going to the even digit, eg 0.00125 to 0.0012.
+/// `HalfUp`: rounded to the nearest, halves going away from zero, eg 0.00125 to 0.0013.
+/// `Truncate`: the extra digits are dropped, eg 0.00129 to 0.0012.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Rounding {
+    Reject,
+    Bankers,
+    HalfUp,
+    Truncate,
+}
+
+impl Rounding {
+    /// Brings an amount to exactly `scale` fractional digits, or None if it's too fine and they're rejected.
+    /// Trailing zeros don't count as too fine, and shorter amounts are padded, so everything prints the same way.
+    pub fn round(&self, amount: Decimal, scale: u32) -> Option<Decimal> {
+        let mut rounded = if amount.normalize().scale() <= scale {
+            amount
+        } else {
+            let strategy = match self {
+                Rounding::Reject => return None,
+                Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+                Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+                Rounding::Truncate => RoundingStrategy::ToZero,
+            };
+            amount.round_dp_with_strategy(scale, strategy)
+        };
+        rounded.rescale(scale);
+        Some(rounded)
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Rounding::Reject),
+            "bankers" => Ok(Rounding::Bankers),
+            "half-up" => Ok(Rounding::HalfUp),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(format!(
+                "unknown rounding {} (expected reject, bankers, half-up or truncate)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Rounding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rounding::Reject => write!(f, "reject"),
+            Rounding::Bankers => write!(f, "bankers"),
+            Rounding::HalfUp => write!(f, "half-up"),
+            Rounding::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+/// Decides which transactions are still applied once an account is locked by a chargeback.
+///
+/// `RejectAll`: nothing is applied until the account is unlocked.
+/// `RejectWithdrawals`: only withdrawals are rejected, funds can still come in.
+/// `AllowDisputes`: deposits and withdrawals are rejected but open disputes can still be resolved or charged back.
+///
+/// An `unlock` is always permitted so support staff can reopen the account.
+/// Transfers out are judged as withdrawals, and the account a transfer credits as if it was a deposit.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LockPolicy {
+    RejectAll,
+    RejectWithdrawals,
+    AllowDisputes,
+}
+
+impl LockPolicy {
+    pub fn permits(&self, typ: &TransactionType) -> bool {
+        match (self, typ) {
+            (_, TransactionType::Unlock) => true,
+            (LockPolicy::RejectAll, _) => false,
+            (LockPolicy::RejectWithdrawals, TransactionType::Withdrawal)
+            | (LockPolicy::RejectWithdrawals, TransactionType::Transfer) => false,
+            (LockPolicy::RejectWithdrawals, _) => true,
+            (LockPolicy::AllowDisputes, TransactionType::Deposit)
+            | (LockPolicy::AllowDisputes, TransactionType::Withdrawal)
+            | (LockPolicy::AllowDisputes, TransactionType::Transfer) => false,
+            (LockPolicy::AllowDisputes, _) => true,
+        }
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-all" => Ok(LockPolicy::RejectAll),
+            "reject-withdrawals" => Ok(LockPolicy::RejectWithdrawals),
+            "allow-disputes" => Ok(LockPolicy::AllowDisputes),
+            _ => Err(format!(
+                "unknown lock policy {} (expected reject-all, reject-withdrawals or allow-disputes)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for LockPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LockPolicy::RejectAll => write!(f, "reject-all"),
+            LockPolicy::RejectWithdrawals => write!(f, "reject-withdrawals"),
+            LockPolicy::AllowDisputes => write!(f, "allow-disputes"),
+        }
+    }
+}
+
+/// Decides whether a transaction can be disputed again once its last dispute was closed.
+///
+/// `Never`: a transaction is only ever disputed once, resolved and charged back are final.
+/// `AfterResolve`: a resolved transaction can be disputed again, a charged back one can't.
+/// `Always`: either can. A chargeback takes what it reversed off the transaction, so nothing is reversed twice.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RedisputePolicy {
+    Never,
+    AfterResolve,
+    Always,
+}
+
+impl RedisputePolicy {
+    pub fn permits(&self, state: DisputeState) -> bool {
+        match (self, state) {
+            (_, DisputeState::Settled) => true,
+            (_, DisputeState::Disputed) => false,
+            (RedisputePolicy::Never, _) => false,
+            (RedisputePolicy::AfterResolve, state) => state == DisputeState::Resolved,
+            (RedisputePolicy::Always, _) => true,
+        }
+    }
+}
+
+impl FromStr for RedisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RedisputePolicy::Never),
+            "after-resolve" => Ok(RedisputePolicy::AfterResolve),
+            "always" => Ok(RedisputePolicy::Always),
+            _ => Err(format!(
+                "unknown redispute policy {} (expected never, after-resolve or always)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for RedisputePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RedisputePolicy::Never => write!(f, "never"),
+            RedisputePolicy::AfterResolve => write!(f, "after-resolve"),
+            RedisputePolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// Decides what a dispute on a withdrawal does. Its funds have already left the account, so there's nothing of them to hold.
+///
+/// `Ignore`: the dispute runs its course but moves no funds, not even when charged back. The chargeback still locks.
+/// `CreditOnChargeback`: nothing is held while disputed, and a chargeback credits the withdrawal back.
+/// `Reject`: withdrawals can't be disputed, the dispute is rejected as `withdrawal_dispute`.
+/// `HoldNegative`: the withdrawal is held as a negative amount, so the funds show as available again while it's disputed,
+/// and a chargeback credits them back for good. A resolve takes them away again, so they can't be withdrawn or
+/// transferred until then.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WithdrawalDisputePolicy {
+    Ignore,
+    CreditOnChargeback,
+    Reject,
+    HoldNegative,
+}
+
+impl WithdrawalDisputePolicy {
+    /// Whether a chargeback of a withdrawal credits it back.
+    pub fn credits(&self) -> bool {
+        match self {
+            WithdrawalDisputePolicy::CreditOnChargeback | WithdrawalDisputePolicy::HoldNegative => {
+                true
+            }
+            WithdrawalDisputePolicy::Ignore | WithdrawalDisputePolicy::Reject => false,
+        }
+    }
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(WithdrawalDisputePolicy::Ignore),
+            "credit-on-chargeback" => Ok(WithdrawalDisputePolicy::CreditOnChargeback),
+            "reject" => Ok(WithdrawalDisputePolicy::Reject),
+            "hold-negative" => Ok(WithdrawalDisputePolicy::HoldNegative),
+            _ => Err(format!(
+                "unknown withdrawal dispute policy {} (expected ignore, credit-on-chargeback, reject or hold-negative)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for WithdrawalDisputePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WithdrawalDisputePolicy::Ignore => write!(f, "ignore"),
+            WithdrawalDisputePolicy::CreditOnChargeback => write!(f, "credit-on-chargeback"),
+            WithdrawalDisputePolicy::Reject => write!(f, "reject"),
+            WithdrawalDisputePolicy::HoldNegative => write!(f, "hold-negative"),
+        }
+    }
+}
+
+/// Decides how long a transaction can be disputed. Once it can't, its record is evicted from the history
+/// and only its id is remembered, so it's still a duplicate, and disputing it is `outside_dispute_window`.
+///
+/// `Forever`: everything is kept and can always be disputed.
+/// `PerClient(n)`: each client's last n deposits, withdrawals and transfers out.
+/// `Transactions(n)`: the last n deposits, withdrawals and transfers to arrive, across all clients, whatever their ids.
+/// `Age(secs)`: transactions no more than that many seconds older than the newest `timestamp` seen.
+/// One without a timestamp is taken to be as old as the newest seen.
+///
+/// A transaction with an open dispute is kept until it's resolved or charged back, whatever the policy.
+/// With several shards each judges the window by what it has seen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RetentionPolicy {
+    Forever,
+    PerClient(usize),
+    Transactions(u32),
+    Age(u64),
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    /// `forever`, `per-client:N`, `transactions:N` or `age:N` with an optional unit, eg `age:90d`.
+    /// Ages are in seconds unless they end in `m`, `h` or `d`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "unknown retention {} (expected forever, per-client:N, transactions:N or age:N, eg age:90d)",
+                s
+            )
+        };
+        let (kind, n) = match s.split_once(':') {
+            Some(split) => split,
+            None if s == "forever" => return Ok(RetentionPolicy::Forever),
+            None => return Err(invalid()),
+        };
+
+        let split = n.find(|c: char| !c.is_ascii_digit()).unwrap_or(n.len());
+        let (value, unit) = n.split_at(split);
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+        if value == 0 {
+            return Err(format!("retention {} must be more than zero", s));
+        }
+
+        match (kind, unit) {
+            ("per-client", "") => Ok(RetentionPolicy::PerClient(value as usize)),
+            ("transactions", "") => u32::try_from(value)
+                .map(RetentionPolicy::Transactions)
+                .map_err(|_| invalid()),
+            ("age", unit) => {
+                let secs = match unit {
+                    "" | "s" => 1,
+                    "m" => 60,
+                    "h" => 60 * 60,
+                    "d" => 24 * 60 * 60,
+                    _ => return Err(invalid()),
+                };
+                Ok(RetentionPolicy::Age(value.saturating_mul(secs)))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for RetentionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RetentionPolicy::Forever => write!(f, "forever"),
+            RetentionPolicy::PerClient(n) => write!(f, "per-client:{}", n),
+            RetentionPolicy::Transactions(n) => write!(f, "transactions:{}", n),
+            RetentionPolicy::Age(secs) => write!(f, "age:{}s", secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn rounding_should_follow_strategy() {
+        let round = |rounding: Rounding, amount| rounding.round(amount, 4);
+
+        assert_eq!(round(Rounding::Reject, dec!(0.00125)), None);
+        assert_eq!(round(Rounding::Bankers, dec!(0.00125)), Some(dec!(0.0012)));
+        assert_eq!(round(Rounding::Bankers, dec!(0.00135)), Some(dec!(0.0014)));
+        assert_eq!(round(Rounding::HalfUp, dec!(0.00125)), Some(dec!(0.0013)));
+        assert_eq!(round(Rounding::Truncate, dec!(0.00129)), Some(dec!(0.0012)));
+
+        // trailing zeros aren't too fine, and everything comes back at the scale.
+        for rounding in [Rounding::Reject, Rounding::HalfUp] {
+            assert_eq!(
+                round(rounding, dec!(1.500000)).unwrap().to_string(),
+                "1.5000"
+            );
+            assert_eq!(round(rounding, dec!(2)).unwrap().to_string(), "2.0000");
+        }
+        assert_eq!(
+            Rounding::HalfUp
+                .round(dec!(1.50000000), 2)
+                .unwrap()
+                .to_string(),
+            "1.50"
+        );
+
+        assert_eq!("half-up".parse::<Rounding>(), Ok(Rounding::HalfUp));
+        assert!("up".parse::<Rounding>().is_err());
+    }
+
+    #[test]
+    fn lock_policy_should_always_permit_unlock() {
+        for policy in [
+            LockPolicy::RejectAll,
+            LockPolicy::RejectWithdrawals,
+            LockPolicy::AllowDisputes,
+        ] {
+            assert!(policy.permits(&TransactionType::Unlock));
+        }
+    }
+
+    #[test]
+    fn lock_policy_should_permit_per_policy() {
+        let typs = [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Transfer,
+        ];
+
+        let permitted =
+            |policy: LockPolicy| typs.iter().map(|t| policy.permits(t)).collect::<Vec<_>>();
+
+        assert_eq!(permitted(LockPolicy::RejectAll), vec![false; 6]);
+        assert_eq!(
+            permitted(LockPolicy::RejectWithdrawals),
+            vec![true, false, true, true, true, false]
+        );
+        assert_eq!(
+            permitted(LockPolicy::AllowDisputes),
+            vec![false, false, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn lock_policy_should_round_trip_through_strings() {
+        for policy in [
+            LockPolicy::RejectAll,
+            LockPolicy::RejectWithdrawals,
+            LockPolicy::AllowDisputes,
+        ] {
+            assert_eq!(policy.to_string().parse::<LockPolicy>(), Ok(policy));
+        }
+        assert!("pirates_rock".parse::<LockPolicy>().is_err());
+    }
+
+    #[test]
+    fn withdrawal_dispute_policy_should_round_trip_through_strings() {
+        for policy in [
+            WithdrawalDisputePolicy::Ignore,
+            WithdrawalDisputePolicy::CreditOnChargeback,
+            WithdrawalDisputePolicy::Reject,
+            WithdrawalDisputePolicy::HoldNegative,
+        ] {
+            assert_eq!(
+                policy.to_string().parse::<WithdrawalDisputePolicy>(),
+                Ok(policy)
+            );
+        }
+        assert!("credit".parse::<WithdrawalDisputePolicy>().is_err());
+    }
+
+    #[test]
+    fn retention_policy_should_parse_with_units() {
+        assert_eq!(
+            "age:90d".parse::<RetentionPolicy>(),
+            Ok(RetentionPolicy::Age(90 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            "per-client:100".parse::<RetentionPolicy>(),
+            Ok(RetentionPolicy::PerClient(100))
+        );
+        for policy in [
+            RetentionPolicy::Forever,
+            RetentionPolicy::PerClient(3),
+            RetentionPolicy::Transactions(1000),
+            RetentionPolicy::Age(60),
+        ] {
+            assert_eq!(policy.to_string().parse::<RetentionPolicy>(), Ok(policy));
+        }
+        for invalid in [
+            "per-client:0",
+            "per-client:5d",
+            "age:1w",
+            "transactions",
+            "days:3",
+        ] {
+            assert!(invalid.parse::<RetentionPolicy>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use crate::client_accounts::{ClientAccounts, Routed};
+use crate::error::EngineError;
+use crate::producer::Row;
+
+/// A row that didn't change any account state, with what's needed to report it.
+///
+/// `ids`: the client and tx of the row, if it could be parsed.
+#[derive(Debug)]
+pub struct Rejected {
+    pub source: Arc<String>,
+    pub line: u64,
+    pub raw: Option<String>,
+    pub ids: Option<(u16, u32)>,
+    pub error: EngineError,
+}
+
+/// Applies a row to the accounts, handing it back as `Rejected` if it was ignored.
+///
+/// `routed`: what the router knows of the row that its shard can't see.
+pub fn apply_row(clients: &mut ClientAccounts, row: Row, routed: Routed) -> Result<(), Rejected> {
+    let (ids, result) = match row.record {
+        Ok(transaction) => (
+            Some((transaction.client(), transaction.tx())),
+            clients.update_routed(transaction, routed),
+        ),
+        Err(e) => (None, Err(e)),
+    };
+
+    result.map_err(|error| Rejected {
+        source: row.source,
+        line: row.line,
+        raw: row.raw,
+        ids,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::transaction::{Transaction, TransactionType};
+
+    use super::*;
+
+    fn row(line: u64, record: Result<Transaction, EngineError>) -> Row {
+        Row {
+            source: Arc::new("txs.csv".into()),
+            input: 0,
+            line,
+            offset: 0,
+            raw: Some("raw".into()),
+            record,
+        }
+    }
+
+    #[test]
+    fn apply_row_should_hand_back_rejected_rows() {
+        let mut clients = ClientAccounts::new();
+        let deposit = || {
+            Transaction::builder(TransactionType::Deposit, 1, 1)
+                .amount(dec!(1.0))
+                .build()
+        };
+
+        assert!(apply_row(&mut clients, row(2, Ok(deposit())), Routed::default()).is_ok());
+
+        let rejected =
+            apply_row(&mut clients, row(3, Ok(deposit())), Routed::default()).unwrap_err();
+        assert_eq!(rejected.line, 3);
+        assert_eq!(rejected.raw.as_deref(), Some("raw"));
+        assert_eq!(rejected.ids, Some((1, 1)));
+        assert!(matches!(rejected.error, EngineError::DuplicateTx(1)));
+
+        let rejected = apply_row(
+            &mut clients,
+            row(4, Err(EngineError::Parse("bad".into()))),
+            Routed::default(),
+        )
+        .unwrap_err();
+        assert_eq!(rejected.ids, None);
+        assert_eq!(rejected.error.code(), "parse_error");
+    }
+}
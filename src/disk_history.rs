@@ -0,0 +1,434 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::EngineError;
+use crate::history::{self, HistoryStore};
+use crate::transaction::TransactionHistoryRecord;
+
+/// Bytes per entry in a run: a big endian tx, so byte order is key order, then the record.
+const ENTRY_LEN: usize = 4 + history::RECORD_LEN;
+/// Entries between index fences. A lookup reads at most one block from each run.
+const BLOCK_ENTRIES: usize = 512;
+/// Fills the record of a removed entry. No record has it as its type, so it can't be mistaken for one.
+const TOMBSTONE: u8 = u8::MAX;
+
+type Key = u32;
+
+/// Keeps the transaction history in a bounded memtable and spills it to sorted run files on disk.
+///
+/// `dir`: scratch directory for the run files. Anything left in it from an earlier run is removed.
+/// `memtable_limit`: records kept in memory before they're flushed to a new run.
+///
+/// Runs are merged like a binary counter, two runs of the same level become one of the next, so there are
+/// only ever log2(flushes) runs and each record is rewritten that many times at most.
+/// Each run keeps every 512th key in memory, so memory is the memtable plus about 1/64 byte per record on disk.
+/// There's no bloom filter: a miss, which every new deposit is, reads one block from each run.
+/// That's cheap while the runs sit in the page cache and the price of a fixed memory budget once they don't.
+/// A removed record leaves a tombstone hiding any older copy, until it's merged into the oldest run where there are none.
+#[derive(Debug)]
+pub struct DiskHistory {
+    dir: PathBuf,
+    memtable: BTreeMap<Key, Option<TransactionHistoryRecord>>,
+    memtable_limit: usize,
+    runs: Vec<Run>, // oldest first.
+    next_run: u64,
+}
+
+/// A sorted, immutable file of entries with a sparse in-memory index.
+#[derive(Debug)]
+struct Run {
+    path: PathBuf,
+    file: File,
+    level: u32,
+    len: usize,
+    fences: Vec<Key>,
+}
+
+impl DiskHistory {
+    pub fn open<P: AsRef<Path>>(dir: P, memtable_limit: usize) -> Result<DiskHistory, EngineError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "run") {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(DiskHistory {
+            dir,
+            memtable: BTreeMap::new(),
+            memtable_limit: memtable_limit.max(1),
+            runs: Vec::new(),
+            next_run: 0,
+        })
+    }
+
+    /// Number of run files currently on disk.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        let memtable = std::mem::take(&mut self.memtable);
+        let run = self.write_run(0, memtable.into_iter().map(Ok))?;
+        self.runs.push(run);
+
+        // merge the newest runs while they're the same level, carrying like a binary counter.
+        while self.runs.len() >= 2
+            && self.runs[self.runs.len() - 1].level == self.runs[self.runs.len() - 2].level
+        {
+            let newer = self.runs.pop().unwrap();
+            let older = self.runs.pop().unwrap();
+            let merged = self.merge(older, newer)?;
+            self.runs.push(merged);
+        }
+
+        Ok(())
+    }
+
+    /// Merges two runs into one of the next level. Where both have a key the newer record wins.
+    fn merge(&mut self, older: Run, newer: Run) -> Result<Run, EngineError> {
+        let level = older.level + 1;
+        let mut sources = [older.entries()?, newer.entries()?];
+        let mut heap = BinaryHeap::new();
+        for (age, source) in sources.iter_mut().enumerate() {
+            if let Some((key, entry)) = source.next().transpose()? {
+                // newest has the smallest age so it's popped first for a key.
+                heap.push(Reverse((key, 1 - age, EntryRecord(entry))));
+            }
+        }
+
+        let mut last = None;
+        let mut merge_error = None;
+        let entries = std::iter::from_fn(|| loop {
+            let Reverse((key, age, EntryRecord(entry))) = heap.pop()?;
+            match sources[1 - age].next().transpose() {
+                Ok(Some((next, next_entry))) => {
+                    heap.push(Reverse((next, age, EntryRecord(next_entry))))
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    merge_error = Some(e);
+                    return None;
+                }
+            }
+            if last != Some(key) {
+                last = Some(key);
+                return Some(Ok((key, entry)));
+            }
+        });
+        let run = self.write_run(level, entries)?;
+        if let Some(e) = merge_error {
+            return Err(e);
+        }
+
+        fs::remove_file(&older.path)?;
+        fs::remove_file(&newer.path)?;
+        Ok(run)
+    }
+
+    /// Writes entries out as a new run, to be pushed after whatever runs are left.
+    /// With none left it's the oldest, so there's nothing for tombstones to hide and they're dropped.
+    fn write_run<I>(&mut self, level: u32, entries: I) -> Result<Run, EngineError>
+    where
+        I: Iterator<Item = Result<(Key, Option<TransactionHistoryRecord>), EngineError>>,
+    {
+        let oldest = self.runs.is_empty();
+        self.next_run += 1;
+        let path = self.dir.join(format!("{:06}.run", self.next_run));
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let mut len = 0;
+        let mut fences = Vec::new();
+        let mut buf = Vec::with_capacity(ENTRY_LEN);
+        for entry in entries {
+            let (tx, record) = entry?;
+            if record.is_none() && oldest {
+                continue;
+            }
+            if len % BLOCK_ENTRIES == 0 {
+                fences.push(tx);
+            }
+
+            buf.clear();
+            buf.extend_from_slice(&tx.to_be_bytes());
+            match record {
+                Some(record) => history::encode_record(&record, &mut buf),
+                None => buf.resize(ENTRY_LEN, TOMBSTONE),
+            }
+            writer.write_all(&buf)?;
+            len += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        Ok(Run {
+            file: File::open(&path)?,
+            path,
+            level,
+            len,
+            fences,
+        })
+    }
+}
+
+impl Run {
+    /// Finds the entry for a key, which is None if the record was removed.
+    fn get(&mut self, key: Key) -> Result<Option<Option<TransactionHistoryRecord>>, EngineError> {
+        // the block is the last one starting at or before the key.
+        let block = match self.fences.binary_search(&key) {
+            Ok(block) => block,
+            Err(0) => return Ok(None),
+            Err(next) => next - 1,
+        };
+
+        let first = block * BLOCK_ENTRIES;
+        let count = BLOCK_ENTRIES.min(self.len - first);
+        let mut buf = vec![0; count * ENTRY_LEN];
+        self.file
+            .seek(SeekFrom::Start((first * ENTRY_LEN) as u64))?;
+        self.file.read_exact(&mut buf)?;
+
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let entry = &buf[mid * ENTRY_LEN..(mid + 1) * ENTRY_LEN];
+            match decode_key(entry).cmp(&key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(decode_entry(entry)?.1)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads every entry in key order.
+    fn entries(
+        &self,
+    ) -> Result<
+        impl Iterator<Item = Result<(Key, Option<TransactionHistoryRecord>), EngineError>>,
+        EngineError,
+    > {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut remaining = self.len;
+        let mut buf = [0; ENTRY_LEN];
+
+        Ok(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+
+            Some(
+                reader
+                    .read_exact(&mut buf)
+                    .map_err(EngineError::from)
+                    .and_then(|_| decode_entry(&buf)),
+            )
+        }))
+    }
+}
+
+fn decode_key(entry: &[u8]) -> Key {
+    u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]])
+}
+
+/// Reads an entry's key and record, or None for a tombstone.
+fn decode_entry(entry: &[u8]) -> Result<(Key, Option<TransactionHistoryRecord>), EngineError> {
+    let record = match entry[4..].iter().all(|b| *b == TOMBSTONE) {
+        true => None,
+        false => Some(history::decode_record(&entry[4..])?.0),
+    };
+    Ok((decode_key(entry), record))
+}
+
+/// Orders records as equal so the heap only ever compares keys and ages.
+struct EntryRecord(Option<TransactionHistoryRecord>);
+
+impl PartialEq for EntryRecord {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for EntryRecord {}
+
+impl PartialOrd for EntryRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EntryRecord {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl HistoryStore for DiskHistory {
+    fn get(&mut self, tx: u32) -> Result<Option<TransactionHistoryRecord>, EngineError> {
+        if let Some(entry) = self.memtable.get(&tx) {
+            return Ok(entry.clone());
+        }
+
+        for run in self.runs.iter_mut().rev() {
+            if let Some(entry) = run.get(tx)? {
+                return Ok(entry);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn insert(&mut self, tx: u32, record: TransactionHistoryRecord) -> Result<(), EngineError> {
+        self.memtable.insert(tx, Some(record));
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: u32) -> Result<(), EngineError> {
+        self.memtable.insert(tx, None);
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compacts everything into a single run first, which is then read in tx order.
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u32, &TransactionHistoryRecord) -> Result<(), EngineError>,
+    ) -> Result<(), EngineError> {
+        if !self.memtable.is_empty() {
+            self.flush()?;
+        }
+        while self.runs.len() > 1 {
+            let newer = self.runs.pop().unwrap();
+            let older = self.runs.pop().unwrap();
+            let merged = self.merge(older, newer)?;
+            self.runs.push(merged);
+        }
+
+        match self.runs.first() {
+            Some(run) => run.entries()?.try_for_each(|entry| match entry? {
+                (tx, Some(record)) => f(tx, &record),
+                (_, None) => Ok(()),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DiskHistory {
+    fn drop(&mut self) {
+        for run in self.runs.iter() {
+            let _ = fs::remove_file(&run.path); // scratch space, nothing to do if it's already gone.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::transaction::{Asset, DisputeState, TransactionType};
+
+    use super::*;
+
+    fn record(amount: i64) -> TransactionHistoryRecord {
+        TransactionHistoryRecord {
+            client: (amount.unsigned_abs() % 3) as u16,
+            typ: TransactionType::Deposit,
+            amount: Decimal::new(amount, 2),
+            asset: Asset::default(),
+            to: None,
+            state: DisputeState::Settled,
+        }
+    }
+
+    #[test]
+    fn disk_history_should_find_records_across_runs() -> Result<(), EngineError> {
+        let dir = std::env::temp_dir().join(format!("te3k-history-{}", std::process::id()));
+        let mut history = DiskHistory::open(&dir, 100)?;
+
+        // insert out of order so runs overlap.
+        for i in 0..5000u32 {
+            let tx = (i * 7919) % 5000;
+            history.insert(tx, record(tx as i64))?;
+        }
+        // newer records win over the copies already flushed.
+        for tx in (0..5000u32).step_by(50) {
+            history.insert(tx, record(-1))?;
+        }
+
+        assert!(history.runs() < 10);
+        for tx in 0..5000u32 {
+            let expected = if tx % 50 == 0 { -1 } else { tx as i64 };
+            assert_eq!(history.get(tx)?, Some(record(expected)));
+        }
+        assert!(!history.contains(5000)?);
+
+        let mut count = 0;
+        let mut last = None;
+        history.for_each(&mut |tx, _| {
+            assert!(last < Some(tx));
+            last = Some(tx);
+            count += 1;
+            Ok(())
+        })?;
+        assert_eq!(count, 5000);
+        assert_eq!(history.runs(), 1);
+        assert_eq!(history.get(50)?, Some(record(-1)));
+
+        drop(history);
+        assert_eq!(fs::read_dir(&dir)?.count(), 0);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn disk_history_should_forget_removed_records() -> Result<(), EngineError> {
+        let dir = std::env::temp_dir().join(format!("te3k-removed-{}", std::process::id()));
+        let mut history = DiskHistory::open(&dir, 100)?;
+
+        for tx in 0..1000u32 {
+            history.insert(tx, record(tx as i64))?;
+        }
+        // removed from the runs on disk and from the memtable, and one put back after.
+        for tx in (0..1000u32).step_by(3) {
+            history.remove(tx)?;
+        }
+        history.insert(999, record(-1))?;
+        history.remove(5000)?;
+
+        for tx in 0..1000u32 {
+            let expected = match tx {
+                999 => Some(record(-1)),
+                tx if tx % 3 == 0 => None,
+                tx => Some(record(tx as i64)),
+            };
+            assert_eq!(history.get(tx)?, expected);
+        }
+
+        let mut count = 0;
+        history.for_each(&mut |tx, _| {
+            assert!(tx % 3 != 0 || tx == 999);
+            count += 1;
+            Ok(())
+        })?;
+        assert_eq!(count, 667);
+        assert_eq!(history.runs[0].len, 667);
+
+        drop(history);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
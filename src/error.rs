@@ -0,0 +1,148 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use crate::transaction::DisputeState;
+
+/// Everything that can go wrong in the engine, from reading input to applying a transaction.
+///
+/// Errors carrying a transaction or client id mean that one transaction was ignored and no account state changed.
+/// The caller decides whether that's fatal - the CLI reports them as rejects and keeps going.
+/// `code()` gives a stable machine-readable reason for each variant.
+#[derive(Debug)]
+pub enum EngineError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Parse(String),
+    InvalidState(String),
+    ShardFailed(usize),
+    InvalidAmount(u32),
+    MissingAmount(u32),
+    DuplicateTx(u32),
+    InsufficientFunds(u32),
+    UnknownTx(u32),
+    WrongClient(u32),
+    InvalidTransfer(u32),
+    AlreadyDisputed(u32),
+    NotDisputed(u32),
+    DisputeClosed(u32, DisputeState),
+    WithdrawalDispute(u32),
+    NotDisputable(u32),
+    OutsideDisputeWindow(u32),
+    AccountLocked(u16),
+    NotLocked(u16),
+    UnknownType(String),
+}
+
+impl EngineError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::Io(_) => "io_error",
+            EngineError::Csv(_) => "csv_error",
+            EngineError::Parse(_) => "parse_error",
+            EngineError::InvalidState(_) => "invalid_state",
+            EngineError::ShardFailed(_) => "shard_failed",
+            EngineError::InvalidAmount(_) => "invalid_amount",
+            EngineError::MissingAmount(_) => "missing_amount",
+            EngineError::DuplicateTx(_) => "duplicate_tx",
+            EngineError::InsufficientFunds(_) => "insufficient_funds",
+            EngineError::UnknownTx(_) => "unknown_tx",
+            EngineError::WrongClient(_) => "wrong_client",
+            EngineError::InvalidTransfer(_) => "invalid_transfer",
+            EngineError::AlreadyDisputed(_) => "already_disputed",
+            EngineError::NotDisputed(_) => "not_disputed",
+            EngineError::DisputeClosed(..) => "dispute_closed",
+            EngineError::WithdrawalDispute(_) => "withdrawal_dispute",
+            EngineError::NotDisputable(_) => "not_disputable",
+            EngineError::OutsideDisputeWindow(_) => "outside_dispute_window",
+            EngineError::AccountLocked(_) => "account_locked",
+            EngineError::NotLocked(_) => "not_locked",
+            EngineError::UnknownType(_) => "unknown_type",
+        }
+    }
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "io error: {}", e),
+            EngineError::Csv(e) => write!(f, "csv error: {}", e),
+            EngineError::Parse(e) => write!(f, "couldn't deserialize: {}", e),
+            EngineError::InvalidState(e) => write!(f, "invalid state: {}", e),
+            EngineError::ShardFailed(shard) => write!(f, "shard {} stopped unexpectedly", shard),
+            EngineError::InvalidAmount(tx) => write!(f, "tx {} has an invalid amount", tx),
+            EngineError::MissingAmount(tx) => write!(f, "tx {} has no amount", tx),
+            EngineError::DuplicateTx(tx) => write!(f, "tx {} was already seen", tx),
+            EngineError::InsufficientFunds(tx) => {
+                write!(f, "tx {} exceeds the available funds", tx)
+            }
+            EngineError::UnknownTx(tx) => write!(f, "tx {} is unknown", tx),
+            EngineError::WrongClient(tx) => write!(f, "tx {} belongs to another client", tx),
+            EngineError::InvalidTransfer(tx) => {
+                write!(f, "tx {} doesn't name another client to transfer to", tx)
+            }
+            EngineError::AlreadyDisputed(tx) => write!(f, "tx {} is already disputed", tx),
+            EngineError::NotDisputed(tx) => write!(f, "tx {} is not disputed", tx),
+            EngineError::DisputeClosed(tx, state) => {
+                write!(f, "tx {} was already {} and can't be again", tx, state)
+            }
+            EngineError::WithdrawalDispute(tx) => {
+                write!(f, "tx {} is a withdrawal and can't be disputed", tx)
+            }
+            EngineError::NotDisputable(tx) => {
+                write!(f, "tx {} failed, so there's nothing to dispute", tx)
+            }
+            EngineError::OutsideDisputeWindow(tx) => {
+                write!(f, "tx {} is outside the dispute window", tx)
+            }
+            EngineError::AccountLocked(client) => write!(f, "client {} is locked", client),
+            EngineError::NotLocked(client) => write!(f, "client {} is not locked", client),
+            EngineError::UnknownType(typ) => write!(f, "unknown transaction type {}", typ),
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EngineError::Io(e) => Some(e),
+            EngineError::Csv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EngineError {
+    fn from(e: io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+
+impl From<csv::Error> for EngineError {
+    fn from(e: csv::Error) -> Self {
+        EngineError::Csv(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_error_should_wrap_io_errors_with_source() {
+        let err: EngineError = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+
+        assert_eq!(err.code(), "io_error");
+        assert_eq!(err.to_string(), "io error: gone");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn engine_error_should_describe_rejected_transactions() {
+        let err = EngineError::DuplicateTx(7);
+
+        assert_eq!(err.code(), "duplicate_tx");
+        assert_eq!(err.to_string(), "tx 7 was already seen");
+        assert!(err.source().is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+
+use rust_decimal::Decimal;
+
+use crate::error::EngineError;
+use crate::transaction::{Asset, DisputeState, TransactionHistoryRecord, TransactionType};
+
+/// Storage for the transaction history, keyed on transaction id.
+///
+/// Transaction ids are unique across clients, so each record carries the client that owns it.
+///
+/// The history is the entire memory cost of a run, so it's pluggable:
+/// `MemoryHistory` keeps it all in a HashMap, `DiskHistory` keeps a bounded amount in memory and the rest on disk.
+/// Lookups take `&mut self` so a store is free to seek around its files.
+pub trait HistoryStore: Debug + Send {
+    fn get(&mut self, tx: u32) -> Result<Option<TransactionHistoryRecord>, EngineError>;
+
+    fn contains(&mut self, tx: u32) -> Result<bool, EngineError> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    /// Inserts a record, replacing any previous record for the same transaction.
+    fn insert(&mut self, tx: u32, record: TransactionHistoryRecord) -> Result<(), EngineError>;
+
+    /// Drops a record once its transaction can no longer be disputed. Removing one that isn't there does nothing.
+    fn remove(&mut self, tx: u32) -> Result<(), EngineError>;
+
+    /// Visits every record in the store in tx order, so saved histories are reproducible. Used to save the history when a run ends.
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u32, &TransactionHistoryRecord) -> Result<(), EngineError>,
+    ) -> Result<(), EngineError>;
+}
+
+/// Keeps the whole history in memory. Fast, but grows with the input.
+#[derive(Debug, Default)]
+pub struct MemoryHistory {
+    map: HashMap<u32, TransactionHistoryRecord>,
+}
+
+impl MemoryHistory {
+    pub fn new() -> MemoryHistory {
+        MemoryHistory::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn get(&mut self, tx: u32) -> Result<Option<TransactionHistoryRecord>, EngineError> {
+        Ok(self.map.get(&tx).cloned())
+    }
+
+    fn contains(&mut self, tx: u32) -> Result<bool, EngineError> {
+        Ok(self.map.contains_key(&tx))
+    }
+
+    fn insert(&mut self, tx: u32, record: TransactionHistoryRecord) -> Result<(), EngineError> {
+        self.map.insert(tx, record);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: u32) -> Result<(), EngineError> {
+        self.map.remove(&tx);
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(u32, &TransactionHistoryRecord) -> Result<(), EngineError>,
+    ) -> Result<(), EngineError> {
+        let mut txs: Vec<u32> = self.map.keys().copied().collect();
+        txs.sort_unstable();
+        txs.iter().try_for_each(|tx| f(*tx, &self.map[tx]))
+    }
+}
+
+/// Appends the binary form of a record to `buf`: the client, a type tag, the amount, the asset,
+/// the client a transfer went to, as a flag then the client, and a dispute state tag.
+pub(crate) fn encode_record(record: &TransactionHistoryRecord, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&record.client.to_le_bytes());
+    buf.push(match record.typ {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::FailedWithdrawal => 2,
+        TransactionType::Transfer => 3,
+        // nothing else is ever recorded in the history.
+        _ => u8::MAX,
+    });
+    buf.extend_from_slice(&record.amount.serialize());
+    buf.extend_from_slice(&record.asset.to_bytes());
+    buf.push(record.to.is_some() as u8);
+    buf.extend_from_slice(&record.to.unwrap_or_default().to_le_bytes());
+    buf.push(match record.state {
+        DisputeState::Settled => 0,
+        DisputeState::Disputed => 1,
+        DisputeState::Resolved => 2,
+        DisputeState::ChargedBack => 3,
+    });
+}
+
+/// Bytes `encode_record` writes for every record.
+pub(crate) const RECORD_LEN: usize = ASSET_END + 3 + 1;
+
+/// Where the asset ends and a transfer's client starts.
+const ASSET_END: usize = 2 + 1 + 16 + Asset::LEN;
+
+/// Reads a record written by `encode_record`, returning it and the number of bytes used.
+pub(crate) fn decode_record(buf: &[u8]) -> Result<(TransactionHistoryRecord, usize), EngineError> {
+    let invalid = || EngineError::Io(io::Error::new(io::ErrorKind::InvalidData, "bad record"));
+
+    let client: [u8; 2] = buf.get(0..2).ok_or_else(invalid)?.try_into().unwrap();
+    let typ = match buf.get(2).ok_or_else(invalid)? {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::FailedWithdrawal,
+        3 => TransactionType::Transfer,
+        _ => return Err(invalid()),
+    };
+    let amount: [u8; 16] = buf.get(3..19).ok_or_else(invalid)?.try_into().unwrap();
+    let asset: [u8; Asset::LEN] = buf
+        .get(19..ASSET_END)
+        .ok_or_else(invalid)?
+        .try_into()
+        .unwrap();
+    let to = match buf.get(ASSET_END..ASSET_END + 3).ok_or_else(invalid)? {
+        [0, 0, 0] => None,
+        [1, lo, hi] => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => return Err(invalid()),
+    };
+    let state = match buf.get(ASSET_END + 3).ok_or_else(invalid)? {
+        0 => DisputeState::Settled,
+        1 => DisputeState::Disputed,
+        2 => DisputeState::Resolved,
+        3 => DisputeState::ChargedBack,
+        _ => return Err(invalid()),
+    };
+
+    Ok((
+        TransactionHistoryRecord {
+            client: u16::from_le_bytes(client),
+            typ,
+            amount: Decimal::deserialize(amount),
+            asset: Asset::from_bytes(asset).ok_or_else(invalid)?,
+            to,
+            state,
+        },
+        RECORD_LEN,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn memory_history_should_insert_and_get() -> Result<(), EngineError> {
+        let mut history = MemoryHistory::new();
+        assert!(history.is_empty());
+
+        history.insert(
+            7,
+            TransactionHistoryRecord {
+                client: 1,
+                typ: TransactionType::Deposit,
+                amount: dec!(1.5),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
+            },
+        )?;
+
+        assert_eq!(history.len(), 1);
+        assert!(history.contains(7)?);
+        assert!(!history.contains(8)?);
+        assert_eq!(
+            history.get(7)?,
+            Some(TransactionHistoryRecord {
+                client: 1,
+                typ: TransactionType::Deposit,
+                amount: dec!(1.5),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
+            })
+        );
+
+        let mut seen = Vec::new();
+        history.for_each(&mut |tx, record| {
+            seen.push((tx, record.client));
+            Ok(())
+        })?;
+        assert_eq!(seen, vec![(7, 1)]);
+
+        history.remove(7)?;
+        history.remove(8)?;
+        assert!(history.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_should_round_trip_through_the_codec() -> Result<(), EngineError> {
+        let records = [
+            TransactionHistoryRecord {
+                client: 1,
+                typ: TransactionType::Deposit,
+                amount: dec!(1.1111),
+                asset: Asset::default(),
+                to: None,
+                state: DisputeState::Settled,
+            },
+            TransactionHistoryRecord {
+                client: u16::MAX,
+                typ: TransactionType::Transfer,
+                amount: dec!(123456789.0001),
+                asset: "USDC".parse().unwrap(),
+                to: Some(3),
+                state: DisputeState::ChargedBack,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for record in records.iter() {
+            encode_record(record, &mut buf);
+        }
+
+        let (first, used) = decode_record(&buf)?;
+        let (second, _) = decode_record(&buf[used..])?;
+        assert_eq!(first, records[0]);
+        assert_eq!(second, records[1]);
+        assert!(decode_record(&buf[..5]).is_err());
+
+        Ok(())
+    }
+}
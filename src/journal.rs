@@ -0,0 +1,595 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rust_decimal::Decimal;
+
+use crate::client_accounts::ClientAccounts;
+use crate::error::EngineError;
+use crate::producer::Position;
+use crate::shard;
+use crate::snapshot;
+use crate::transaction::{Asset, Transaction, TransactionType};
+
+const MAGIC: &[u8; 4] = b"TE3J";
+const VERSION: u16 = 5;
+const MANIFEST: &str = "MANIFEST";
+
+/// A row that only moved the position: it couldn't be parsed, or its type changes nothing.
+const SKIPPED: u8 = 1;
+/// A row routed to a shard, with its transaction so it can be applied again.
+const APPLIED: u8 = 2;
+/// Everything before it is on disk, along with the rejects it gave, and how long the rejects file was then.
+const FLUSHED: u8 = 3;
+
+/// Bytes of the position every entry starts with, after its kind.
+const POSITION_LEN: usize = 4 + 8 + 8;
+/// Bytes of the transaction an `APPLIED` entry carries.
+const TRANSACTION_LEN: usize = 1 + 2 + 4 + 1 + 16 + Asset::LEN + 3 + 9;
+
+/// Bytes of a `FLUSHED` entry after its kind.
+const FLUSHED_LEN: usize = 1 + 8;
+
+/// Entries written between flushes. A crash loses at most these, which are read from the input again on resume.
+const FLUSH_EVERY: u64 = 1024;
+
+/// An append-only journal of the rows consumed, with periodic snapshots of the complete state, for crash recovery.
+///
+/// `dir`: holds the journal, the per-shard state files and a `MANIFEST` naming the latest complete snapshot.
+/// `every`: take a snapshot after this many rows, after which the journal starts over.
+///
+/// Everything is numbered by generation. A snapshot is written to the next generation's state files and only
+/// counts once the manifest is renamed over the old one, so a crash at any point leaves the last complete
+/// snapshot and its journal in place. Resuming loads that snapshot, applies the journal up to its last flush on top
+/// and carries on reading from the position of the last row flushed.
+///
+/// Each flush is synced to disk and records how long the rejects file was, with every reject of the rows before it
+/// written, so a resume can cut the rejects file back to match and nothing is reported twice or not at all.
+pub struct Journal {
+    dir: PathBuf,
+    gen: u64,
+    writer: BufWriter<File>,
+    position: Position,
+    rejects: Option<u64>,
+    every: u64,
+    since_snapshot: u64,
+    since_flush: u64,
+}
+
+impl Journal {
+    /// Starts a new journal in `dir`, with the state `shards` start from as its first snapshot,
+    /// so a resume gets back whatever they were seeded with, eg by `--snapshot-in`.
+    /// `rejects`: how long the rejects file is to begin with, if there is one.
+    ///
+    /// Clears the journal an earlier run left in `dir`, but refuses a directory holding anything else
+    /// rather than risk deleting it.
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        every: u64,
+        shards: &mut [ClientAccounts],
+        rejects: Option<u64>,
+    ) -> Result<Journal, EngineError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut stale = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if path.is_file() && is_journal_file(name) => stale.push(path),
+                _ => {
+                    return Err(EngineError::InvalidState(format!(
+                        "{} holds {}, which isn't part of a journal",
+                        dir.display(),
+                        path.display()
+                    )))
+                }
+            }
+        }
+        for path in stale {
+            fs::remove_file(path)?;
+        }
+
+        for (shard, clients) in shards.iter_mut().enumerate() {
+            shard::persist(clients, state_path(&dir, 0, shard))?;
+        }
+        let position = Position::default();
+        write_manifest(&dir, 0, shards.len(), position, rejects)?;
+        Ok(Journal {
+            writer: BufWriter::new(File::create(journal_path(&dir, 0))?),
+            dir,
+            gen: 0,
+            position,
+            rejects,
+            every: every.max(1),
+            since_snapshot: 0,
+            since_flush: 0,
+        })
+    }
+
+    /// Loads the latest snapshot in `dir` into `shards` and applies the journal after it, up to its last flush,
+    /// returning the journal to carry on with. `position()` is where to carry on reading, and `rejects()` how much
+    /// of the rejects file to keep.
+    ///
+    /// `shards` should be empty, but needn't be as many as when the journal was written.
+    pub fn resume<P: AsRef<Path>>(
+        dir: P,
+        every: u64,
+        shards: &mut [ClientAccounts],
+    ) -> Result<Journal, EngineError> {
+        let dir = dir.as_ref().to_path_buf();
+        let (gen, count, mut position, mut rejects) = read_manifest(&dir)?;
+
+        for shard in 0..count {
+            let file = File::open(state_path(&dir, gen, shard))?;
+            snapshot::read_snapshot(BufReader::new(file), shards)?;
+        }
+
+        let path = journal_path(&dir, gen);
+        let mut reader = BufReader::new(File::open(&path)?);
+        let (mut read, mut valid) = (0, 0);
+        let mut pending = Vec::new();
+        while let Some((entry, len)) = read_entry(&mut reader)? {
+            read += len as u64;
+            match entry {
+                Entry::Row(row_position, transaction) => pending.push((row_position, transaction)),
+                Entry::Flushed(flushed_rejects) => {
+                    for (row_position, transaction) in pending.drain(..) {
+                        if let Some(transaction) = transaction {
+                            // their rejects were written before the flush, so they're in the file already.
+                            let _ = shard::apply_to(shards, transaction);
+                        }
+                        position = row_position;
+                    }
+                    rejects = flushed_rejects;
+                    valid = read;
+                }
+            }
+        }
+
+        // drop whatever came after the last flush, as its rejects may not have been written. Its rows are read again.
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(valid)?;
+
+        Ok(Journal {
+            dir,
+            gen,
+            writer: BufWriter::new(file),
+            position,
+            rejects,
+            every: every.max(1),
+            since_snapshot: 0,
+            since_flush: 0,
+        })
+    }
+
+    /// Where reading the inputs should carry on from.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// How long the rejects file was at the last flush, if there was one.
+    pub fn rejects(&self) -> Option<u64> {
+        self.rejects
+    }
+
+    /// Records a consumed row: its position, and its transaction if it was routed to a shard.
+    /// Returns true when a flush is due, once the row's rejects are written.
+    pub fn append(
+        &mut self,
+        position: Position,
+        transaction: Option<&Transaction>,
+    ) -> Result<bool, EngineError> {
+        let mut buf = Vec::with_capacity(1 + POSITION_LEN + TRANSACTION_LEN);
+        match transaction.and_then(|t| type_tag(&t.typ).map(|tag| (t, tag))) {
+            Some((transaction, tag)) => {
+                buf.push(APPLIED);
+                encode_position(position, &mut buf);
+                buf.push(tag);
+                buf.extend_from_slice(&transaction.client.to_le_bytes());
+                buf.extend_from_slice(&transaction.tx.to_le_bytes());
+                buf.push(transaction.amount.is_some() as u8);
+                buf.extend_from_slice(&transaction.amount.unwrap_or_default().serialize());
+                buf.extend_from_slice(&transaction.asset.to_bytes());
+                buf.push(transaction.to.is_some() as u8);
+                buf.extend_from_slice(&transaction.to.unwrap_or_default().to_le_bytes());
+                buf.push(transaction.timestamp.is_some() as u8);
+                buf.extend_from_slice(&transaction.timestamp.unwrap_or_default().to_le_bytes());
+            }
+            // unknown types change nothing so there's nothing to apply again.
+            None => {
+                buf.push(SKIPPED);
+                encode_position(position, &mut buf);
+            }
+        }
+        self.writer.write_all(&buf)?;
+        self.position = position;
+
+        self.since_flush += 1;
+        self.since_snapshot += 1;
+        Ok(self.since_flush >= FLUSH_EVERY || self.snapshot_due())
+    }
+
+    /// Whether enough rows have been journaled since the last snapshot to take another.
+    pub fn snapshot_due(&self) -> bool {
+        self.since_snapshot >= self.every
+    }
+
+    /// Syncs everything appended to disk. Must only be called once the rejects of every row appended so far are
+    /// in the rejects file, which is `rejects` long.
+    pub fn flush(&mut self, rejects: Option<u64>) -> Result<(), EngineError> {
+        let mut buf = [0; 1 + FLUSHED_LEN];
+        buf[0] = FLUSHED;
+        buf[1] = rejects.is_some() as u8;
+        buf[2..].copy_from_slice(&rejects.unwrap_or_default().to_le_bytes());
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.since_flush = 0;
+        self.rejects = rejects;
+        Ok(())
+    }
+
+    /// The files each of `shards` shards should persist their state to for the next snapshot.
+    pub fn snapshot_paths(&self, shards: usize) -> Vec<PathBuf> {
+        (0..shards)
+            .map(|shard| state_path(&self.dir, self.gen + 1, shard))
+            .collect()
+    }
+
+    /// Makes the state written to `snapshot_paths` the point to resume from and starts a new, empty journal.
+    /// Must only be called once every shard has written its file, straight after a flush.
+    pub fn commit_snapshot(&mut self, shards: usize) -> Result<(), EngineError> {
+        let gen = self.gen + 1;
+        let writer = BufWriter::new(File::create(journal_path(&self.dir, gen))?);
+        write_manifest(&self.dir, gen, shards, self.position, self.rejects)?;
+
+        // the old generation is no longer needed once the manifest points past it.
+        let old = std::mem::replace(&mut self.gen, gen);
+        self.writer = writer;
+        self.since_snapshot = 0;
+        self.since_flush = 0;
+        let _ = fs::remove_file(journal_path(&self.dir, old));
+        for shard in 0.. {
+            if fs::remove_file(state_path(&self.dir, old, shard)).is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a file name is one a journal writes, and so safe to clear.
+fn is_journal_file(name: &str) -> bool {
+    let numbered = |prefix, extension| name.starts_with(prefix) && name.ends_with(extension);
+    name == MANIFEST
+        || name == format!("{}.tmp", MANIFEST)
+        || numbered("journal-", ".log")
+        || numbered("state-", ".bin")
+}
+
+fn journal_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("journal-{:06}.log", gen))
+}
+
+fn state_path(dir: &Path, gen: u64, shard: usize) -> PathBuf {
+    dir.join(format!("state-{:06}-{:03}.bin", gen, shard))
+}
+
+/// Writes the manifest next to the old one and renames it over, so it's always either the old or the new.
+fn write_manifest(
+    dir: &Path,
+    gen: u64,
+    shards: usize,
+    position: Position,
+    rejects: Option<u64>,
+) -> Result<(), EngineError> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&gen.to_le_bytes());
+    buf.extend_from_slice(&(shards as u32).to_le_bytes());
+    encode_position(position, &mut buf);
+    buf.push(rejects.is_some() as u8);
+    buf.extend_from_slice(&rejects.unwrap_or_default().to_le_bytes());
+
+    let tmp = dir.join(format!("{}.tmp", MANIFEST));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(MANIFEST))?;
+    Ok(())
+}
+
+fn read_manifest(dir: &Path) -> Result<(u64, usize, Position, Option<u64>), EngineError> {
+    let mut buf = Vec::new();
+    File::open(dir.join(MANIFEST))
+        .map_err(|e| {
+            EngineError::InvalidState(format!("nothing to resume in {}: {}", dir.display(), e))
+        })?
+        .read_to_end(&mut buf)?;
+
+    if buf.len() != 4 + 2 + 8 + 4 + POSITION_LEN + FLUSHED_LEN || &buf[..4] != MAGIC {
+        return Err(EngineError::InvalidState("not a journal manifest".into()));
+    }
+    let version = u16::from_le_bytes([buf[4], buf[5]]);
+    if version != VERSION {
+        return Err(EngineError::InvalidState(format!(
+            "unsupported journal version {}",
+            version
+        )));
+    }
+
+    let gen = u64::from_le_bytes(buf[6..14].try_into().unwrap());
+    let shards = u32::from_le_bytes(buf[14..18].try_into().unwrap()) as usize;
+    let position = decode_position(&buf[18..18 + POSITION_LEN]);
+    Ok((
+        gen,
+        shards,
+        position,
+        decode_rejects(&buf[18 + POSITION_LEN..]),
+    ))
+}
+
+fn encode_position(position: Position, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(position.input as u32).to_le_bytes());
+    buf.extend_from_slice(&position.line.to_le_bytes());
+    buf.extend_from_slice(&position.offset.to_le_bytes());
+}
+
+fn decode_position(buf: &[u8]) -> Position {
+    Position {
+        input: u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize,
+        line: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+        offset: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
+    }
+}
+
+fn decode_rejects(buf: &[u8]) -> Option<u64> {
+    (buf[0] != 0).then(|| u64::from_le_bytes(buf[1..9].try_into().unwrap()))
+}
+
+enum Entry {
+    /// A row's position, and its transaction if it was routed to a shard.
+    Row(Position, Option<Transaction>),
+    /// A flush, with how long the rejects file was.
+    Flushed(Option<u64>),
+}
+
+/// Reads the next whole entry and its length, or None at the end of the journal or a half written entry.
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<(Entry, usize)>, EngineError> {
+    let mut kind = [0; 1];
+    if !read_full(reader, &mut kind)? {
+        return Ok(None);
+    }
+
+    if kind[0] == FLUSHED {
+        let mut buf = [0; FLUSHED_LEN];
+        if !read_full(reader, &mut buf)? {
+            return Ok(None);
+        }
+        return Ok(Some((
+            Entry::Flushed(decode_rejects(&buf)),
+            1 + FLUSHED_LEN,
+        )));
+    }
+
+    let mut position = [0; POSITION_LEN];
+    if !read_full(reader, &mut position)? {
+        return Ok(None);
+    }
+    let position = decode_position(&position);
+
+    match kind[0] {
+        SKIPPED => Ok(Some((Entry::Row(position, None), 1 + POSITION_LEN))),
+        APPLIED => {
+            let mut buf = [0; TRANSACTION_LEN];
+            if !read_full(reader, &mut buf)? {
+                return Ok(None);
+            }
+            let typ = parse_type_tag(buf[0])?;
+            let mut transaction = Transaction::builder(
+                typ,
+                u16::from_le_bytes([buf[1], buf[2]]),
+                u32::from_le_bytes(buf[3..7].try_into().unwrap()),
+            );
+            if buf[7] != 0 {
+                transaction =
+                    transaction.amount(Decimal::deserialize(buf[8..24].try_into().unwrap()));
+            }
+            let asset = Asset::from_bytes(buf[24..32].try_into().unwrap())
+                .ok_or_else(|| EngineError::InvalidState("invalid asset in journal".into()))?;
+            transaction = transaction.asset(asset);
+            if buf[32] != 0 {
+                transaction = transaction.to(u16::from_le_bytes([buf[33], buf[34]]));
+            }
+            if buf[35] != 0 {
+                transaction =
+                    transaction.timestamp(u64::from_le_bytes(buf[36..44].try_into().unwrap()));
+            }
+            Ok(Some((
+                Entry::Row(position, Some(transaction.build())),
+                1 + POSITION_LEN + TRANSACTION_LEN,
+            )))
+        }
+        kind => Err(EngineError::InvalidState(format!(
+            "unexpected journal entry {}",
+            kind
+        ))),
+    }
+}
+
+/// Fills `buf`, returning false if the reader ran out first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, EngineError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn type_tag(typ: &TransactionType) -> Option<u8> {
+    match typ {
+        TransactionType::Deposit => Some(0),
+        TransactionType::Withdrawal => Some(1),
+        TransactionType::Dispute => Some(2),
+        TransactionType::Resolve => Some(3),
+        TransactionType::Chargeback => Some(4),
+        TransactionType::Unlock => Some(5),
+        TransactionType::Transfer => Some(6),
+        TransactionType::FailedWithdrawal | TransactionType::Unknown(_) => None,
+    }
+}
+
+fn parse_type_tag(tag: u8) -> Result<TransactionType, EngineError> {
+    match tag {
+        0 => Ok(TransactionType::Deposit),
+        1 => Ok(TransactionType::Withdrawal),
+        2 => Ok(TransactionType::Dispute),
+        3 => Ok(TransactionType::Resolve),
+        4 => Ok(TransactionType::Chargeback),
+        5 => Ok(TransactionType::Unlock),
+        6 => Ok(TransactionType::Transfer),
+        _ => Err(EngineError::InvalidState(format!(
+            "unknown journal transaction type {}",
+            tag
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction::builder(TransactionType::Deposit, client, tx)
+            .amount(dec!(1.5))
+            .build()
+    }
+
+    fn at(line: u64) -> Position {
+        Position {
+            input: 0,
+            line,
+            offset: line * 10,
+        }
+    }
+
+    #[test]
+    fn journal_should_resume_from_snapshot_and_journal() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("te3k-journal-{}", std::process::id()));
+        let mut live = [ClientAccounts::new(), ClientAccounts::new()];
+        let mut journal = Journal::create(&dir, 2, &mut live, Some(10))?;
+
+        let apply = |journal: &mut Journal, live: &mut [ClientAccounts], line, tx| {
+            let transaction = deposit((tx % 2) as u16, tx);
+            live[(tx % 2) as usize].update(transaction.clone()).unwrap();
+            journal.append(at(line), Some(&transaction)).unwrap()
+        };
+
+        assert!(!apply(&mut journal, &mut live, 2, 1));
+        assert!(apply(&mut journal, &mut live, 3, 2));
+        assert!(journal.snapshot_due());
+        journal.flush(Some(20))?;
+        for (path, clients) in journal.snapshot_paths(2).into_iter().zip(live.iter_mut()) {
+            snapshot::write_snapshot(std::slice::from_mut(clients), File::create(path)?)?;
+        }
+        journal.commit_snapshot(2)?;
+
+        apply(&mut journal, &mut live, 4, 3);
+        journal.append(at(5), None)?;
+        journal.flush(Some(30))?;
+        // rows after the last flush are read again, as their rejects may not have been written.
+        apply(&mut journal, &mut live, 6, 5);
+        journal.writer.flush()?;
+        drop(journal);
+
+        // a half written entry from a crash is dropped.
+        let log = journal_path(&dir, 1);
+        OpenOptions::new()
+            .append(true)
+            .open(&log)?
+            .write_all(&[APPLIED, 1, 2])?;
+
+        let mut resumed = [ClientAccounts::new(), ClientAccounts::new()];
+        let mut journal = Journal::resume(&dir, 2, &mut resumed)?;
+        assert_eq!(journal.position(), at(5));
+        assert_eq!(journal.rejects(), Some(30));
+        assert_eq!(resumed[1].get(1).unwrap().total(), dec!(3.0));
+        assert_eq!(resumed[0].get(0).unwrap().total(), dec!(1.5));
+        assert!(matches!(
+            shard::apply_to(&mut resumed, deposit(0, 3)),
+            Err(EngineError::DuplicateTx(3))
+        ));
+
+        // and it carries on after the last good entry.
+        journal.append(at(6), Some(&deposit(0, 4)))?;
+        journal.flush(Some(40))?;
+        drop(journal);
+        let mut again = [ClientAccounts::new(), ClientAccounts::new()];
+        assert_eq!(Journal::resume(&dir, 2, &mut again)?.position(), at(6));
+        assert_eq!(again[0].get(0).unwrap().total(), dec!(3.0));
+
+        // a snapshot keeps what the rejects file was at the flush before it.
+        let mut live = [ClientAccounts::new(), ClientAccounts::new()];
+        let mut journal = Journal::resume(&dir, 1, &mut live)?;
+        journal.flush(Some(50))?;
+        for (path, clients) in journal.snapshot_paths(2).into_iter().zip(live.iter_mut()) {
+            snapshot::write_snapshot(std::slice::from_mut(clients), File::create(path)?)?;
+        }
+        journal.commit_snapshot(2)?;
+        drop(journal);
+        let mut again = [ClientAccounts::new(), ClientAccounts::new()];
+        let journal = Journal::resume(&dir, 2, &mut again)?;
+        assert_eq!((journal.position(), journal.rejects()), (at(6), Some(50)));
+        drop(journal);
+
+        // into any number of shards.
+        let mut single = [ClientAccounts::new()];
+        Journal::resume(&dir, 2, &mut single)?;
+        assert_eq!(single[0].get(0).unwrap().total(), dec!(3.0));
+        assert_eq!(single[0].get(1).unwrap().total(), dec!(3.0));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn journal_should_resume_a_seeded_start() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("te3k-journal-seeded-{}", std::process::id()));
+        let mut seeded = [ClientAccounts::new()];
+        seeded[0].update(deposit(1, 1))?;
+
+        // a directory holding anything else is left alone.
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("notes.txt"), "keep me")?;
+        assert!(Journal::create(&dir, 100, &mut seeded, None).is_err());
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_file(dir.join("notes.txt"))?;
+
+        let mut journal = Journal::create(&dir, 100, &mut seeded, None)?;
+        seeded[0].update(deposit(1, 2))?;
+        journal.append(at(2), Some(&deposit(1, 2)))?;
+        journal.flush(None)?;
+        drop(journal);
+
+        let mut resumed = [ClientAccounts::new()];
+        Journal::resume(&dir, 100, &mut resumed)?;
+        assert_eq!(resumed[0].get(1).unwrap().total(), dec!(3.0));
+        assert!(matches!(
+            resumed[0].update(deposit(1, 1)),
+            Err(EngineError::DuplicateTx(1))
+        ));
+
+        // starting again clears the old journal.
+        Journal::create(&dir, 100, &mut [ClientAccounts::new()], None)?;
+        let mut again = [ClientAccounts::new()];
+        Journal::resume(&dir, 100, &mut again)?;
+        assert!(again[0].is_empty());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! The Turboencabulator 3000 engine: ingests transactions and keeps client account state.
+//!
+//! The CLI in `main.rs` is a thin layer over this crate. Services can link the engine directly:
+//!
+//! ```
+//! use rust_decimal_macros::dec;
+//! use turboencabulator::{ClientAccounts, Transaction, TransactionType};
+//!
+//! let mut accounts = ClientAccounts::new();
+//! accounts
+//!     .update(Transaction::builder(TransactionType::Deposit, 1, 1).amount(dec!(1.5)).build())
+//!     .unwrap();
+//!
+//! let account = accounts.get(1).unwrap();
+//! assert_eq!(account.available(), dec!(1.5));
+//! assert!(!account.locked());
+//! ```
+
+pub mod checkpoint;
+pub mod client_accounts;
+pub mod config;
+pub mod consumer;
+pub mod disk_history;
+pub mod error;
+pub mod history;
+pub mod journal;
+pub mod output;
+pub mod producer;
+pub mod rejects;
+pub mod retention;
+pub mod shard;
+pub mod snapshot;
+pub mod state;
+pub mod transaction;
+
+pub use client_accounts::{AccountSnapshot, ClientAccount, ClientAccounts};
+pub use config::{
+    EngineConfig, LockPolicy, RedisputePolicy, RetentionPolicy, Rounding, WithdrawalDisputePolicy,
+};
+pub use disk_history::DiskHistory;
+pub use error::EngineError;
+pub use history::{HistoryStore, MemoryHistory};
+pub use transaction::{DisputeState, Transaction, TransactionBuilder, TransactionType};
@@ -1,25 +1,150 @@
 extern crate clap;
 
-use std::time::Instant;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use csv::ReaderBuilder;
-use csv::Trim::All;
 use tokio::sync::mpsc;
+use tokio::time::{self, Interval};
 
-use client_accounts::ClientAccounts;
-
-mod client_accounts;
-mod transaction;
+use turboencabulator::checkpoint::{self, Checkpointer};
+use turboencabulator::consumer::Rejected;
+use turboencabulator::journal::Journal;
+use turboencabulator::output::{self, OutputFormat, SortOrder};
+use turboencabulator::producer::{self, InputFormat, Position};
+use turboencabulator::rejects::RejectsWriter;
+use turboencabulator::shard::{RejectHandler, Shards};
+use turboencabulator::{snapshot, state};
+use turboencabulator::{
+    ClientAccounts, DiskHistory, EngineConfig, EngineError, HistoryStore, LockPolicy,
+    MemoryHistory, RedisputePolicy, RetentionPolicy, Rounding, WithdrawalDisputePolicy,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// One or more transaction files or glob patterns, processed in the order given.
+    /// Reads from stdin when omitted or given as `-`.
     #[clap(value_parser)]
-    transactions_file: String,
+    transactions_files: Vec<String>,
 
     #[clap(short, parse(from_flag))]
     debug: bool,
+
+    /// Writes every row that didn't change account state to this csv, with a reason code.
+    #[clap(long, value_parser)]
+    rejects: Option<PathBuf>,
+
+    /// Most fractional digits an amount may have, on input and in the accounts printed.
+    #[clap(long, visible_alias = "max-scale", value_parser, default_value_t = 4)]
+    scale: u32,
+
+    /// What happens to amounts with more digits than `--scale`: reject, bankers, half-up or truncate.
+    /// Printed amounts are rounded the same way, or half to even when rejecting.
+    #[clap(long, value_parser, default_value_t = Rounding::Reject)]
+    rounding: Rounding,
+
+    /// Format of the inputs: csv or jsonl. Detected from each file's extension if not given,
+    /// `.jsonl` and `.ndjson` being json lines and anything else csv.
+    #[clap(long, value_parser)]
+    input_format: Option<InputFormat>,
+
+    /// Format of the accounts printed at the end: csv, json or jsonl.
+    #[clap(long, value_parser, default_value_t = OutputFormat::Csv)]
+    output_format: OutputFormat,
+
+    /// Order of the accounts printed at the end: client, available, held or total, with an optional -asc or -desc,
+    /// eg total-desc. Ties are broken by client id.
+    #[clap(long, value_parser, default_value_t = SortOrder::default())]
+    sort: SortOrder,
+
+    /// Also prints each account's open dispute and transaction counts.
+    #[clap(long, parse(from_flag))]
+    extra_fields: bool,
+
+    /// What a locked account may still do: reject-all, reject-withdrawals or allow-disputes.
+    #[clap(long, value_parser, default_value_t = LockPolicy::AllowDisputes)]
+    lock_policy: LockPolicy,
+
+    /// Whether a transaction can be disputed again once its dispute is closed: never, after-resolve or always.
+    #[clap(long, value_parser, default_value_t = RedisputePolicy::AfterResolve)]
+    redispute: RedisputePolicy,
+
+    /// What disputing a withdrawal does: ignore, credit-on-chargeback, reject or hold-negative.
+    #[clap(long, value_parser, default_value_t = WithdrawalDisputePolicy::CreditOnChargeback)]
+    withdrawal_disputes: WithdrawalDisputePolicy,
+
+    /// How long transactions can be disputed before their history is dropped:
+    /// forever, per-client:N, transactions:N or age:N, eg age:90d against the `timestamp` column.
+    #[clap(long, value_parser, default_value_t = RetentionPolicy::Forever)]
+    retention: RetentionPolicy,
+
+    /// Writes a numbered state file after every N rows.
+    #[clap(long, value_parser)]
+    checkpoint_every: Option<u64>,
+
+    /// Writes a numbered state file on an interval, eg 500ms, 60s, 5m or 1h.
+    #[clap(long, value_parser = checkpoint::parse_interval)]
+    checkpoint_interval: Option<Duration>,
+
+    /// Path prefix for checkpoint files, eg `out/checkpoint` writes `out/checkpoint-000001.csv`.
+    #[clap(long, value_parser, default_value = "checkpoint")]
+    checkpoint_prefix: PathBuf,
+
+    /// Number of consumers to shard client accounts across, keyed on client id.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 1)]
+    shards: u16,
+
+    /// Keeps the transaction history on disk under this directory, one scratch directory per shard,
+    /// so memory stays bounded however big the input is. Kept in memory when omitted.
+    #[clap(long, value_parser)]
+    history_dir: Option<PathBuf>,
+
+    /// Transactions each shard keeps in memory before spilling them to `--history-dir`.
+    #[clap(long, value_parser, default_value_t = 1_000_000)]
+    history_memtable: usize,
+
+    /// Starts from the accounts csv printed by an earlier run instead of empty accounts.
+    #[clap(long, value_parser)]
+    initial_state: Option<PathBuf>,
+
+    /// The history saved by the earlier run with `--history-out`, so its transactions can still be disputed.
+    /// Needed if any account has held funds.
+    #[clap(long, value_parser, requires = "initial-state")]
+    initial_history: Option<PathBuf>,
+
+    /// Saves the transaction history here at the end of the run, to be passed to the next one as `--initial-history`.
+    #[clap(long, value_parser)]
+    history_out: Option<PathBuf>,
+
+    /// Starts from the complete state saved by an earlier run with `--snapshot-out`.
+    #[clap(long, value_parser, conflicts_with = "initial-state")]
+    snapshot_in: Option<PathBuf>,
+
+    /// Saves the complete state, accounts, history and open disputes, as a binary file at the end of the run.
+    #[clap(long, value_parser)]
+    snapshot_out: Option<PathBuf>,
+
+    /// Journals every row to this directory, with periodic snapshots of the complete state,
+    /// so a run that crashes can be carried on with `--resume`.
+    #[clap(long, value_parser)]
+    journal: Option<PathBuf>,
+
+    /// Rows between the journal's snapshots. The journal starts over after each one.
+    #[clap(long, value_parser, default_value_t = 1_000_000)]
+    journal_snapshot_every: u64,
+
+    /// Carries on from where the run journaling to `--journal` stopped. Give it the same inputs.
+    #[clap(
+        long,
+        parse(from_flag),
+        requires = "journal",
+        conflicts_with_all = &["initial-state", "snapshot-in"]
+    )]
+    resume: bool,
 }
 
 #[tokio::main]
@@ -27,12 +152,56 @@ async fn main() {
     let now = Instant::now(); // used to present total runtime.
 
     let args = Args::parse();
-    let file_path = args.transactions_file;
     let debug = args.debug;
+    let inputs = match producer::expand_inputs(&args.transactions_files) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = EngineConfig {
+        max_scale: args.scale,
+        rounding: args.rounding,
+        lock_policy: args.lock_policy,
+        redispute: args.redispute,
+        withdrawal_disputes: args.withdrawal_disputes,
+        retention: args.retention,
+    };
+    let clients = match open_stores(&args) {
+        Ok(stores) => stores
+            .into_iter()
+            .map(|store| ClientAccounts::with_store(config.clone(), store))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("couldn't open history: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let rejects = args
+        .rejects
+        .as_ref()
+        .map(|path| open_rejects(path, args.resume));
+    let mut rejects = match rejects.transpose() {
+        Ok(rejects) => rejects,
+        Err(e) => {
+            eprintln!("couldn't create rejects file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let (clients, mut journal) = match restore(&args, clients, rejects.as_mut()) {
+        Ok(restored) => restored,
+        Err(e) => {
+            eprintln!("couldn't restore state: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     if debug {
         println!("\nStarting...");
-        println!("\tInput file: {}", file_path);
+        for input in inputs.iter() {
+            println!("\tInput file: {}", input);
+        }
         println!("\tResult:\n");
     }
 
@@ -42,40 +211,281 @@ async fn main() {
     // The csv parsing is delegated to another thread which will stream the transaction records back to this main thread.
     let (tx, mut rx) = mpsc::channel(2048);
 
-    // This would be, for example, a kafka consumer reading sets of transactions from a topic.
-    // Any multiplexing would require some work to
-    // ensure only one set of transactions processed at a time as transactions are ordered.
-    tokio::spawn(async move {
-        let mut rdr = ReaderBuilder::new()
-            .trim(All) // ensures whitespace ignored.
-            .from_path(file_path)
-            .unwrap(); // Fails thread on missing file.
-
-        for result in rdr.deserialize() {
-            // ignores any records that fail.
-            if let Ok(record) = result {
-                let r = tx.send(record).await;
-                if r.is_err() {
-                    println!("issue transmitting... {:?}", r)
+    let from = journal
+        .as_ref()
+        .map_or(Position::default(), Journal::position);
+    let producer = tokio::spawn(producer::produce(
+        inputs,
+        from,
+        args.input_format,
+        tx,
+        rejects.is_some(),
+        debug,
+    ));
+
+    // rejects are reported from whichever shard hit them.
+    let rejects = Arc::new(Mutex::new(rejects));
+    let on_reject: RejectHandler = {
+        let rejects = rejects.clone();
+        Arc::new(move |rejected| report(&rejects, rejected))
+    };
+
+    let mut shards = match Shards::spawn(clients, 2048, on_reject) {
+        Ok(shards) => shards,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut checkpointer = Checkpointer::new(args.checkpoint_prefix, args.checkpoint_every);
+    // the first tick is a full interval in so we don't checkpoint an empty state.
+    let mut ticker = args
+        .checkpoint_interval
+        .map(|every| time::interval_at(time::Instant::now() + every, every));
+
+    loop {
+        let due = tokio::select! {
+            row = rx.recv() => match row {
+                Some(row) => {
+                    let position = row.position();
+                    let transaction = match (&journal, &row.record) {
+                        (Some(_), Ok(transaction)) => Some(transaction.clone()),
+                        _ => None,
+                    };
+
+                    if let Err(e) = shards.apply(row).await {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+
+                    if let Some(journal) = journal.as_mut() {
+                        let result = journal.append(position, transaction.as_ref());
+                        let result = match result {
+                            Ok(true) => flush_journal(journal, &mut shards, &rejects).await,
+                            other => other.map(|_| ()),
+                        };
+                        if let Err(e) = result {
+                            eprintln!("couldn't write journal: {}", e);
+                            std::process::exit(1);
+                        }
+                        if journal.snapshot_due() {
+                            if let Err(e) = snapshot_journal(journal, &mut shards).await {
+                                eprintln!("couldn't snapshot journal: {}", e);
+                            }
+                        }
+                    }
+
+                    checkpointer.record_row()
                 }
-            } else {
-                println!("couldn't deserialize {:?}", result);
+                None => break,
+            },
+            _ = tick(ticker.as_mut()) => true,
+        };
+
+        if due {
+            let written = shards.snapshot().await.and_then(|mut s| {
+                s.iter_mut().for_each(|s| s.round(&config));
+                checkpointer.write(&s)
+            });
+            match written {
+                Ok(path) if debug => println!("\tCheckpoint: {}", path.display()),
+                Ok(_) => (),
+                Err(e) => eprintln!("couldn't write checkpoint: {}", e),
             }
         }
-    });
+    }
+
+    if let Some(journal) = journal.as_mut() {
+        if let Err(e) = flush_journal(journal, &mut shards, &rejects).await {
+            eprintln!("couldn't write journal: {}", e);
+        }
+    }
 
-    let mut clients = ClientAccounts::new();
+    let mut clients = match shards.join().await {
+        Ok(clients) => clients,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    while let Some(message) = rx.recv().await {
-        clients.update(message).unwrap(); // Note: fails main thread on unknown transaction type.
+    if let Some(Err(e)) = rejects.lock().unwrap().as_mut().map(|r| r.flush()) {
+        eprintln!("couldn't write rejects: {}", e);
     }
 
-    let csv_res = clients.write_csv(Box::new(std::io::stdout()));
+    // the channel only closes once the producer is done, so this won't wait.
+    match producer.await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            eprintln!("couldn't read input: {}", e);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("producer failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    if debug || csv_res.is_err() {
+    if let Some(path) = &args.snapshot_out {
+        let result = File::create(path)
+            .map_err(EngineError::from)
+            .and_then(|file| snapshot::write_snapshot(&mut clients, BufWriter::new(file)));
+        if let Err(e) = result {
+            eprintln!("couldn't write snapshot: {}", e);
+        }
+    }
+
+    if let Some(path) = &args.history_out {
+        let result = File::create(path)
+            .map_err(EngineError::from)
+            .and_then(|file| state::write_history(&mut clients, BufWriter::new(file)));
+        if let Err(e) = result {
+            eprintln!("couldn't write history: {}", e);
+        }
+    }
+
+    let mut snapshots: Vec<_> = clients.iter().flat_map(ClientAccounts::snapshot).collect();
+    snapshots.iter_mut().for_each(|s| s.round(&config));
+    args.sort.sort(&mut snapshots);
+    let output_res = output::write_accounts(
+        &snapshots,
+        args.output_format,
+        args.extra_fields,
+        BufWriter::new(std::io::stdout()),
+    );
+
+    if debug || output_res.is_err() {
         let elapsed = now.elapsed();
         println!("\nCompleted run.");
-        println!("\tResult: {:?}", csv_res);
+        println!("\tResult: {:?}", output_res);
         println!("\tTook: {:.2?}", elapsed);
     }
 }
+
+/// Reports an ignored row to the rejects file, or STDERR if it couldn't be parsed and there's no file.
+fn report(rejects: &Mutex<Option<RejectsWriter<BufWriter<File>>>>, rejected: Rejected) {
+    match rejects.lock().unwrap().as_mut() {
+        Some(rejects) => {
+            let raw = rejected.raw.as_deref().unwrap_or_default();
+            let result = rejects.write(
+                &rejected.source,
+                rejected.line,
+                raw,
+                rejected.ids,
+                &rejected.error,
+            );
+            if let Err(e) = result {
+                eprintln!("couldn't write reject: {}", e);
+            }
+        }
+        // keep stdout clean for the account csv.
+        None if rejected.ids.is_none() => {
+            eprintln!("{}:{} {}", rejected.source, rejected.line, rejected.error)
+        }
+        None => (),
+    }
+}
+
+/// Waits for the next checkpoint tick, or forever if there's no interval.
+async fn tick(ticker: Option<&mut Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Opens a history store for each shard.
+fn open_stores(args: &Args) -> Result<Vec<Box<dyn HistoryStore>>, EngineError> {
+    (0..args.shards)
+        .map(|shard| -> Result<Box<dyn HistoryStore>, EngineError> {
+            Ok(match &args.history_dir {
+                Some(dir) => Box::new(DiskHistory::open(
+                    dir.join(format!("shard-{}", shard)),
+                    args.history_memtable,
+                )?),
+                None => Box::new(MemoryHistory::new()),
+            })
+        })
+        .collect()
+}
+
+/// Seeds the shards' accounts from `--resume`, `--snapshot-in`, or `--initial-state` and `--initial-history`,
+/// and opens the journal if there is one.
+/// A resumed journal cuts `rejects` back to what it had at the journal's last flush.
+fn restore(
+    args: &Args,
+    mut clients: Vec<ClientAccounts>,
+    rejects: Option<&mut RejectsWriter<BufWriter<File>>>,
+) -> Result<(Vec<ClientAccounts>, Option<Journal>), EngineError> {
+    if let Some(path) = &args.snapshot_in {
+        snapshot::read_snapshot(BufReader::new(File::open(path)?), &mut clients)?;
+    }
+    if let Some(path) = &args.initial_state {
+        let history = args.initial_history.as_ref().map(File::open).transpose()?;
+        state::restore(File::open(path)?, history, &mut clients)?;
+    }
+
+    // a new journal starts from the seeded state, so it has to be loaded first.
+    let journal = match &args.journal {
+        Some(dir) if args.resume => {
+            let journal = Journal::resume(dir, args.journal_snapshot_every, &mut clients)?;
+            if let (Some(rejects), Some(len)) = (rejects, journal.rejects()) {
+                rejects.truncate(len)?;
+            }
+            Some(journal)
+        }
+        Some(dir) => {
+            let rejects = rejects.map(RejectsWriter::sync).transpose()?.flatten();
+            Some(Journal::create(
+                dir,
+                args.journal_snapshot_every,
+                &mut clients,
+                rejects,
+            )?)
+        }
+        None => None,
+    };
+    Ok((clients, journal))
+}
+
+/// Waits for the shards to report the rejects of every row journaled, then syncs both files to disk.
+async fn flush_journal(
+    journal: &mut Journal,
+    shards: &mut Shards,
+    rejects: &Mutex<Option<RejectsWriter<BufWriter<File>>>>,
+) -> Result<(), EngineError> {
+    shards.catch_up().await?;
+    let len = rejects
+        .lock()
+        .unwrap()
+        .as_mut()
+        .map(RejectsWriter::sync)
+        .transpose()?
+        .flatten();
+    journal.flush(len)
+}
+
+/// Has every shard save its state for the journal, then makes that the point to resume from.
+/// The journal has to have just been flushed.
+async fn snapshot_journal(journal: &mut Journal, shards: &mut Shards) -> Result<(), EngineError> {
+    shards.persist(journal.snapshot_paths(shards.len())).await?;
+    journal.commit_snapshot(shards.len())
+}
+
+/// Creates the rejects file, or carries on the existing one when resuming.
+fn open_rejects(
+    path: &PathBuf,
+    resume: bool,
+) -> Result<RejectsWriter<BufWriter<File>>, EngineError> {
+    if resume && path.exists() {
+        let file = OpenOptions::new().append(true).open(path)?;
+        let writer = RejectsWriter::append(BufWriter::new(file.try_clone()?));
+        return Ok(writer.with_file(file));
+    }
+    let file = File::create(path)?;
+    let writer = RejectsWriter::new(BufWriter::new(file.try_clone()?))?;
+    Ok(writer.with_file(file))
+}
@@ -0,0 +1,347 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::client_accounts::AccountSnapshot;
+use crate::error::EngineError;
+use crate::transaction::Asset;
+
+/// How the final state of the accounts is written.
+///
+/// `Csv`: a header then a row per account, `id,available,held,total,locked`.
+/// `Json`: a single array holding an object per account.
+/// `Jsonl`: an object per account, one per line.
+///
+/// There's a row per asset a client holds. The asset is only written once something other than the default is held,
+/// as an `asset` column after `id`, or an `asset` field on just those accounts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(format!(
+                "unknown output format {} (expected csv, json or jsonl)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+/// What the accounts are ordered by when written. Ties are always broken by client id then asset, so output is reproducible.
+///
+/// Parsed from a field name, `client`, `available`, `held` or `total`, with an optional `-asc` or `-desc`,
+/// eg `total-desc`. Ascending is the default.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SortOrder {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SortField {
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+impl SortOrder {
+    pub fn sort(&self, snapshots: &mut [AccountSnapshot]) {
+        snapshots.sort_unstable_by(|a, b| {
+            let order = match self.field {
+                SortField::Client => a.client.cmp(&b.client),
+                SortField::Available => a.available.cmp(&b.available),
+                SortField::Held => a.held.cmp(&b.held),
+                SortField::Total => a.total.cmp(&b.total),
+            };
+            let order = if self.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            order
+                .then(a.client.cmp(&b.client))
+                .then(a.asset.cmp(&b.asset))
+        });
+    }
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder {
+            field: SortField::Client,
+            descending: false,
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, descending) = match s.rsplit_once('-') {
+            Some((field, "desc")) => (field, true),
+            Some((field, "asc")) => (field, false),
+            _ => (s, false),
+        };
+        let field = match field {
+            "client" => SortField::Client,
+            "available" => SortField::Available,
+            "held" => SortField::Held,
+            "total" => SortField::Total,
+            _ => {
+                return Err(format!(
+                    "unknown sort {} (expected client, available, held or total, optionally with -asc or -desc)",
+                    s
+                ))
+            }
+        };
+        Ok(SortOrder { field, descending })
+    }
+}
+
+impl Display for SortOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let field = match self.field {
+            SortField::Client => "client",
+            SortField::Available => "available",
+            SortField::Held => "held",
+            SortField::Total => "total",
+        };
+        match self.descending {
+            true => write!(f, "{}-desc", field),
+            false => write!(f, "{}", field),
+        }
+    }
+}
+
+/// An account as written to json, with named fields.
+/// Amounts are strings so they keep their exact value and scale through any json parser.
+#[derive(Debug, Serialize)]
+struct AccountRecord<'a> {
+    client: u16,
+    #[serde(skip_serializing_if = "Asset::is_default")]
+    asset: Asset,
+    available: &'a Decimal,
+    held: &'a Decimal,
+    total: &'a Decimal,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_disputes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transactions: Option<u64>,
+}
+
+impl AccountRecord<'_> {
+    fn new(snapshot: &AccountSnapshot, extras: bool) -> AccountRecord<'_> {
+        AccountRecord {
+            client: snapshot.client,
+            asset: snapshot.asset,
+            available: &snapshot.available,
+            held: &snapshot.held,
+            total: &snapshot.total,
+            locked: snapshot.locked,
+            open_disputes: extras.then_some(snapshot.open_disputes),
+            transactions: extras.then_some(snapshot.transactions),
+        }
+    }
+}
+
+/// Writes the accounts in the given format.
+///
+/// `extras`: also writes each account's open dispute and transaction counts,
+/// as `open_disputes` and `transactions` columns or fields.
+pub fn write_accounts<W: Write>(
+    snapshots: &[AccountSnapshot],
+    format: OutputFormat,
+    extras: bool,
+    mut writer: W,
+) -> Result<(), EngineError> {
+    match format {
+        OutputFormat::Csv => return write_csv(snapshots, extras, writer),
+        OutputFormat::Json => {
+            let records: Vec<_> = snapshots
+                .iter()
+                .map(|s| AccountRecord::new(s, extras))
+                .collect();
+            serde_json::to_writer(&mut writer, &records).map_err(io::Error::from)?;
+            writer.write_all(b"\n")?;
+        }
+        OutputFormat::Jsonl => {
+            for s in snapshots {
+                serde_json::to_writer(&mut writer, &AccountRecord::new(s, extras))
+                    .map_err(io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Columns are only added when needed, so single asset output without extras is the same as it always was.
+fn write_csv<W: Write>(
+    snapshots: &[AccountSnapshot],
+    extras: bool,
+    writer: W,
+) -> Result<(), EngineError> {
+    let assets = snapshots.iter().any(|s| !s.asset.is_default());
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    let mut record = csv::StringRecord::new();
+    record.push_field("id");
+    if assets {
+        record.push_field("asset");
+    }
+    record.extend(["available", "held", "total", "locked"]);
+    if extras {
+        record.extend(["open_disputes", "transactions"]);
+    }
+    wtr.write_record(&record)?;
+
+    for s in snapshots {
+        record.clear();
+        record.push_field(&s.client.to_string());
+        if assets {
+            record.push_field(s.asset.as_str());
+        }
+        record.push_field(&s.available.to_string());
+        record.push_field(&s.held.to_string());
+        record.push_field(&s.total.to_string());
+        record.push_field(&s.locked.to_string());
+        if extras {
+            record.push_field(&s.open_disputes.to_string());
+            record.push_field(&s.transactions.to_string());
+        }
+        wtr.write_record(&record)?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn snapshots() -> Vec<AccountSnapshot> {
+        vec![AccountSnapshot {
+            client: 1,
+            asset: Asset::default(),
+            available: dec!(1.50),
+            held: dec!(0.25),
+            total: dec!(1.75),
+            locked: false,
+            open_disputes: 1,
+            transactions: 3,
+        }]
+    }
+
+    fn write(format: OutputFormat, extras: bool) -> String {
+        let mut buf = Vec::new();
+        write_accounts(&snapshots(), format, extras, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn sort_order_should_break_ties_by_client() {
+        let account = |client, total| AccountSnapshot {
+            client,
+            asset: Asset::default(),
+            available: total,
+            held: dec!(0),
+            total,
+            locked: false,
+            open_disputes: 0,
+            transactions: 0,
+        };
+        let mut snapshots = vec![
+            account(3, dec!(1)),
+            account(1, dec!(5)),
+            account(2, dec!(1)),
+            account(4, dec!(1.00)),
+        ];
+
+        "total-desc"
+            .parse::<SortOrder>()
+            .unwrap()
+            .sort(&mut snapshots);
+        let clients: Vec<u16> = snapshots.iter().map(|s| s.client).collect();
+        assert_eq!(clients, vec![1, 2, 3, 4]);
+
+        SortOrder::default().sort(&mut snapshots);
+        "client-desc"
+            .parse::<SortOrder>()
+            .unwrap()
+            .sort(&mut snapshots);
+        let clients: Vec<u16> = snapshots.iter().map(|s| s.client).collect();
+        assert_eq!(clients, vec![4, 3, 2, 1]);
+
+        assert!("total-sideways".parse::<SortOrder>().is_err());
+        assert_eq!("held-asc".parse::<SortOrder>().unwrap().to_string(), "held");
+    }
+
+    #[test]
+    fn write_accounts_should_name_fields() {
+        assert_eq!(
+            write(OutputFormat::Json, false),
+            "[{\"client\":1,\"available\":\"1.50\",\"held\":\"0.25\",\"total\":\"1.75\",\"locked\":false}]\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Jsonl, true),
+            "{\"client\":1,\"available\":\"1.50\",\"held\":\"0.25\",\"total\":\"1.75\",\"locked\":false,\"open_disputes\":1,\"transactions\":3}\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Csv, false),
+            "id,available,held,total,locked\n1,1.50,0.25,1.75,false\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Csv, true),
+            "id,available,held,total,locked,open_disputes,transactions\n1,1.50,0.25,1.75,false,1,3\n"
+        );
+
+        // the asset is only written once there's one other than the default.
+        let mut snapshots = snapshots();
+        snapshots.push(AccountSnapshot {
+            asset: "BTC".parse().unwrap(),
+            ..snapshots[0].clone()
+        });
+        let mut buf = Vec::new();
+        write_accounts(&snapshots, OutputFormat::Csv, false, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "id,asset,available,held,total,locked\n1,,1.50,0.25,1.75,false\n1,BTC,1.50,0.25,1.75,false\n"
+        );
+        let mut buf = Vec::new();
+        write_accounts(&snapshots[1..], OutputFormat::Jsonl, false, &mut buf).unwrap();
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .starts_with("{\"client\":1,\"asset\":\"BTC\","));
+    }
+}
@@ -0,0 +1,487 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use csv::Trim::All;
+use csv::{ReaderBuilder, StringRecord};
+use glob::glob;
+use tokio::sync::mpsc::Sender;
+
+use crate::error::EngineError;
+use crate::transaction::{self, Transaction};
+
+/// A source of transactions: either a file on disk or stdin.
+#[derive(Debug)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Input::Stdin => Ok(Box::new(io::stdin())),
+            Input::File(path) => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Opens the input part way through. Only files can be, stdin can't be rewound or skipped.
+    fn open_at(&self, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Input::Stdin => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stdin can't be resumed part way through",
+            )),
+            Input::File(path) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+}
+
+/// How an input's rows are written.
+///
+/// `Csv`: a header row then one transaction per row.
+/// `Jsonl`: one json object per line, eg `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Picks the format from the file extension: `.jsonl` and `.ndjson` are json lines, anything else, stdin included, is csv.
+    pub fn detect(input: &Input) -> InputFormat {
+        match input {
+            Input::File(path) => match path.extension().and_then(|e| e.to_str()) {
+                Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+                _ => InputFormat::Csv,
+            },
+            Input::Stdin => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            _ => Err(format!(
+                "unknown input format {} (expected csv or jsonl)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for InputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InputFormat::Csv => write!(f, "csv"),
+            InputFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+impl Display for Input {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Stdin => write!(f, "<stdin>"),
+            Input::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A single row read by the producer and sent to the consumer.
+///
+/// `source`: the input the row was read from.
+/// `input`: index of that input, in the order they're read.
+/// `line`: line number in the source, starting at 1 for the csv header or the first json line.
+/// `offset`: byte offset in the source just past the row, where reading would resume.
+/// `raw`: the row as read, only kept when asked for as it costs an allocation per row.
+/// `record`: the deserialized transaction, or why it couldn't be.
+#[derive(Debug)]
+pub struct Row {
+    pub source: Arc<String>,
+    pub input: usize,
+    pub line: u64,
+    pub offset: u64,
+    pub raw: Option<String>,
+    pub record: Result<Transaction, EngineError>,
+}
+
+impl Row {
+    pub fn position(&self) -> Position {
+        Position {
+            input: self.input,
+            line: self.line,
+            offset: self.offset,
+        }
+    }
+}
+
+/// How far through the inputs reading got: the input, and the line and byte offset just past the last row read.
+/// The default is the very start, before the first input's header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub input: usize,
+    pub line: u64,
+    pub offset: u64,
+}
+
+/// Expands any glob patterns in the provided arguments, keeping argument order.
+///
+/// `-` or no arguments at all means stdin.
+/// Matches for a single pattern are sorted by path. Plain paths are passed through untouched
+/// so a missing file still surfaces when it's opened. A pattern that matches nothing is an error.
+pub fn expand_inputs(args: &[String]) -> Result<Vec<Input>, String> {
+    if args.is_empty() {
+        return Ok(vec![Input::Stdin]);
+    }
+
+    let mut inputs = Vec::new();
+
+    for arg in args {
+        if arg == "-" {
+            inputs.push(Input::Stdin);
+            continue;
+        }
+
+        if !arg.contains(['*', '?', '[']) {
+            inputs.push(Input::File(PathBuf::from(arg)));
+            continue;
+        }
+
+        let matches = glob(arg).map_err(|e| format!("invalid pattern {}: {}", arg, e))?;
+        let mut found = matches
+            .collect::<Result<Vec<PathBuf>, _>>()
+            .map_err(|e| format!("couldn't read {}: {}", arg, e))?;
+        if found.is_empty() {
+            return Err(format!("no files match {}", arg));
+        }
+        found.sort();
+        inputs.extend(found.into_iter().map(Input::File));
+    }
+
+    Ok(inputs)
+}
+
+/// Reads each input in order and streams every row over the channel.
+///
+/// This would be, for example, a kafka consumer reading sets of transactions from a topic.
+/// Any multiplexing would require some work to
+/// ensure only one set of transactions processed at a time as transactions are ordered.
+/// Files are read one after another by the same producer so ordering is kept across files.
+/// Stdin goes through the same reader and channel so piped input gets the same backpressure.
+///
+/// Rows that fail to deserialize are still sent so the consumer can report them.
+/// Stops at the first input that can't be opened and returns the error.
+///
+/// `from`: where an earlier run got to. Earlier inputs are skipped and that input is read from just past its last row.
+/// `format`: the format of every input, or None to detect it from each one's extension.
+pub async fn produce(
+    inputs: Vec<Input>,
+    from: Position,
+    format: Option<InputFormat>,
+    tx: Sender<Row>,
+    keep_raw: bool,
+    debug: bool,
+) -> Result<(), EngineError> {
+    for (index, input) in inputs.iter().enumerate().skip(from.input) {
+        let reader = Reader {
+            input,
+            index,
+            source: Arc::new(input.to_string()),
+            from: if from.input == index {
+                from
+            } else {
+                Position::default()
+            },
+            keep_raw,
+        };
+        let rows = match format.unwrap_or_else(|| InputFormat::detect(input)) {
+            InputFormat::Csv => reader.csv(&tx).await?,
+            InputFormat::Jsonl => reader.jsonl(&tx).await?,
+        };
+
+        if debug {
+            println!("\tRead {} rows from {}", rows, input);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a single input, from the start or wherever `from` says an earlier run got to.
+struct Reader<'a> {
+    input: &'a Input,
+    index: usize,
+    source: Arc<String>,
+    from: Position,
+    keep_raw: bool,
+}
+
+impl Reader<'_> {
+    async fn csv(&self, tx: &Sender<Row>) -> Result<u64, EngineError> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(All) // ensures whitespace ignored.
+            .from_reader(Recorder::new(self.input.open()?, self.keep_raw));
+        let headers = rdr.headers()?.clone();
+        let header_end = rdr.position().byte();
+        rdr.get_mut().take(header_end);
+
+        // lines and offsets are counted from wherever the reader starts.
+        let (base_line, base_offset) = match self.from.offset {
+            0 => (0, 0),
+            offset => {
+                rdr = ReaderBuilder::new()
+                    .trim(All)
+                    .has_headers(false)
+                    .from_reader(Recorder::new(self.input.open_at(offset)?, self.keep_raw));
+                (self.from.line, offset)
+            }
+        };
+
+        let mut rows = 0;
+        let mut record = StringRecord::new();
+        loop {
+            let result = rdr.read_record(&mut record);
+            let end = rdr.position().byte();
+            let raw = rdr.get_mut().take(end);
+            let offset = base_offset + end;
+            let row = match result {
+                Ok(false) => break,
+                Ok(true) => self.row(
+                    base_line + record.position().map_or(0, |p| p.line()),
+                    offset,
+                    raw,
+                    record
+                        .deserialize(Some(&headers))
+                        .map_err(|e| EngineError::Parse(e.to_string())),
+                ),
+                Err(e) => self.row(
+                    base_line + e.position().map_or(0, |p| p.line()),
+                    offset,
+                    raw,
+                    Err(EngineError::Parse(e.to_string())),
+                ),
+            };
+            rows += 1;
+            send(tx, row).await;
+        }
+
+        Ok(rows)
+    }
+
+    /// Blank lines are skipped, anything else that isn't a transaction is sent as a parse error.
+    async fn jsonl(&self, tx: &Sender<Row>) -> Result<u64, EngineError> {
+        let mut rdr = BufReader::new(match self.from.offset {
+            0 => self.input.open()?,
+            offset => self.input.open_at(offset)?,
+        });
+
+        let (mut line, mut offset) = (self.from.line, self.from.offset);
+        let mut rows = 0;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            let read = match rdr.read_line(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) => {
+                    // not utf-8, or the input failing underneath. Nothing after it can be trusted to line up.
+                    let row = self.row(
+                        line + 1,
+                        offset,
+                        None,
+                        Err(EngineError::Parse(e.to_string())),
+                    );
+                    send(tx, row).await;
+                    return Ok(rows + 1);
+                }
+            };
+            line += 1;
+            offset += read as u64;
+
+            let text = buf.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let row = self.row(
+                line,
+                offset,
+                self.keep_raw.then(|| text.to_string()),
+                transaction::from_json(text).map_err(|e| EngineError::Parse(e.to_string())),
+            );
+            rows += 1;
+            send(tx, row).await;
+        }
+
+        Ok(rows)
+    }
+
+    fn row(
+        &self,
+        line: u64,
+        offset: u64,
+        raw: Option<String>,
+        record: Result<Transaction, EngineError>,
+    ) -> Row {
+        Row {
+            source: self.source.clone(),
+            input: self.index,
+            line,
+            offset,
+            raw,
+            record,
+        }
+    }
+}
+
+/// Keeps what the csv reader reads ahead, so each row can be taken back exactly as it was written,
+/// quotes and whitespace included, rather than put back together from its fields.
+///
+/// `keep`: whether to keep anything, rows are only wanted when asked for.
+/// `start`: the byte offset of `buf` from where the reader started.
+struct Recorder<R> {
+    inner: R,
+    keep: bool,
+    buf: Vec<u8>,
+    start: u64,
+}
+
+impl<R> Recorder<R> {
+    fn new(inner: R, keep: bool) -> Recorder<R> {
+        Recorder {
+            inner,
+            keep,
+            buf: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// Takes everything up to `end`, the offset just past a row, as that row without its line endings.
+    /// Blank lines the csv reader skipped before it go too.
+    fn take(&mut self, end: u64) -> Option<String> {
+        if !self.keep {
+            return None;
+        }
+        let len = (end - self.start) as usize;
+        let raw = String::from_utf8_lossy(&self.buf[..len])
+            .trim_matches(['\r', '\n'])
+            .to_string();
+        self.buf.drain(..len);
+        self.start = end;
+        Some(raw)
+    }
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if self.keep {
+            self.buf.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+async fn send(tx: &Sender<Row>, row: Row) {
+    let r = tx.send(row).await;
+    if r.is_err() {
+        println!("issue transmitting... {:?}", r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    async fn read(input: Input, from: Position) -> Vec<Row> {
+        let (tx, mut rx) = mpsc::channel(16);
+        produce(vec![input], from, None, tx, true, false)
+            .await
+            .unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = rx.recv().await {
+            rows.push(row);
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn produce_should_read_and_resume_json_lines() {
+        let path = std::env::temp_dir().join(format!("te3k-input-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\"}\n",
+                "\n",
+                "{\"type\":\"deposit\",\n",
+                "{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":0.25}\n",
+            ),
+        )
+        .unwrap();
+        let input = || Input::File(path.clone());
+        assert_eq!(InputFormat::detect(&input()), InputFormat::Jsonl);
+
+        let rows = read(input(), Position::default()).await;
+        let lines: Vec<u64> = rows.iter().map(|r| r.line).collect();
+        assert_eq!(lines, vec![1, 3, 4]);
+        assert!(rows[1].record.is_err());
+        assert_eq!(rows[2].record.as_ref().unwrap().amount(), Some(dec!(0.25)));
+
+        // carrying on from the first row skips the rest of it and keeps counting lines.
+        let resumed = read(input(), rows[0].position()).await;
+        assert_eq!(resumed.len(), 2);
+        assert_eq!(resumed[1].position(), rows[2].position());
+        assert_eq!(resumed[1].raw, rows[2].raw);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn produce_should_keep_csv_rows_as_written() {
+        let path = std::env::temp_dir().join(format!("te3k-input-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                "type, client, tx, amount\r\n",
+                "deposit,1,1,\"1,5\"\r\n",
+                "\r\n",
+                "withdrawal,  1, 2 , 0.5\r\n",
+                "deposit,1,3,\"2.0\"",
+            ),
+        )
+        .unwrap();
+
+        let rows = read(Input::File(path.clone()), Position::default()).await;
+        let raw: Vec<_> = rows.iter().map(|r| r.raw.as_deref().unwrap()).collect();
+        assert_eq!(
+            raw,
+            vec![
+                "deposit,1,1,\"1,5\"",
+                "withdrawal,  1, 2 , 0.5",
+                "deposit,1,3,\"2.0\""
+            ]
+        );
+        assert!(rows[0].record.is_err());
+        assert_eq!(rows[2].record.as_ref().unwrap().amount(), Some(dec!(2.0)));
+
+        let resumed = read(Input::File(path.clone()), rows[0].position()).await;
+        assert_eq!(resumed[0].raw, rows[1].raw);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::Write;
+
+use csv::Writer;
+
+use crate::error::EngineError;
+
+/// Writes one csv row per rejected input row so it can be reconciled against the source.
+///
+/// `client` and `tx` are left empty when the row couldn't be parsed.
+/// The reason column is the error's `code()`.
+pub struct RejectsWriter<T: Write> {
+    wtr: Writer<T>,
+    /// A handle on the file being written to, if it's one, for `sync` and `truncate`.
+    file: Option<File>,
+}
+
+impl<T: Write> RejectsWriter<T> {
+    pub fn new(writer: T) -> Result<RejectsWriter<T>, EngineError> {
+        let mut wtr = Writer::from_writer(writer);
+        wtr.write_record(["source", "line", "raw", "client", "tx", "reason"])?;
+        Ok(RejectsWriter { wtr, file: None })
+    }
+
+    /// Carries on a rejects file that already has its header, eg when resuming a run.
+    pub fn append(writer: T) -> RejectsWriter<T> {
+        RejectsWriter {
+            wtr: Writer::from_writer(writer),
+            file: None,
+        }
+    }
+
+    /// Lets `sync` and `truncate` reach the file `writer` writes to, through a handle of its own.
+    pub fn with_file(self, file: File) -> RejectsWriter<T> {
+        RejectsWriter {
+            file: Some(file),
+            ..self
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        source: &str,
+        line: u64,
+        raw: &str,
+        ids: Option<(u16, u32)>,
+        rejection: &EngineError,
+    ) -> Result<(), EngineError> {
+        let (client, tx) = ids.map_or((String::new(), String::new()), |(client, tx)| {
+            (client.to_string(), tx.to_string())
+        });
+        self.wtr.write_record([
+            source,
+            &line.to_string(),
+            raw,
+            &client,
+            &tx,
+            rejection.code(),
+        ])?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), EngineError> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the rejects written so far and syncs them to disk, returning how long the file is.
+    /// Without a file it only flushes.
+    pub fn sync(&mut self) -> Result<Option<u64>, EngineError> {
+        self.flush()?;
+        match &self.file {
+            Some(file) => {
+                file.sync_data()?;
+                Ok(Some(file.metadata()?.len()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Drops anything written to the file past `len`, eg the rejects of rows a resumed run reads again.
+    pub fn truncate(&mut self, len: u64) -> Result<(), EngineError> {
+        self.flush()?;
+        if let Some(file) = &self.file {
+            if len < file.metadata()?.len() {
+                file.set_len(len)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io::BufWriter;
+
+    use super::*;
+
+    #[test]
+    fn rejects_writer_should_write_header_and_rows() -> Result<(), Box<dyn Error>> {
+        let mut buf = BufWriter::new(Vec::new());
+
+        let mut rejects = RejectsWriter::new(&mut buf)?;
+        rejects.write(
+            "txs.csv",
+            3,
+            "deposit,1,1,1.0",
+            Some((1, 1)),
+            &EngineError::DuplicateTx(1),
+        )?;
+        rejects.write("txs.csv", 4, "", None, &EngineError::Parse("bad".into()))?;
+        rejects.flush()?;
+        drop(rejects);
+
+        let string = String::from_utf8(buf.into_inner()?)?;
+        assert_eq!(
+            string,
+            "source,line,raw,client,tx,reason\n\
+             txs.csv,3,\"deposit,1,1,1.0\",1,1,duplicate_tx\n\
+             txs.csv,4,,,,parse_error\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+
+use crate::config::RetentionPolicy;
+
+/// Tracks which transactions are still inside the dispute window, and remembers the ids of those evicted from it.
+///
+/// `policy`: how long the window is.
+/// `clock`: the newest `timestamp` seen, for `Age`.
+/// `next`: orders transactions as they're kept, for `PerClient` and `Transactions`.
+/// `kept`: the transactions in the window, oldest first, for `Transactions` and `Age`.
+/// `per_client`: the same per client, oldest first, for `PerClient`.
+/// `over`: the clients whose window has grown past its size since the last `expired`, for `PerClient`.
+/// `evicted`: the ids of every transaction that's left the window.
+/// `pinned`: transactions that left the window with a dispute open, and their client. They're evicted once it closes.
+/// `reported`: the ids evicted since they were last taken, only kept once asked for, eg by a shard router.
+///
+/// Nothing is tracked under `Forever`.
+#[derive(Debug)]
+pub struct Retention {
+    policy: RetentionPolicy,
+    clock: u64,
+    next: u64,
+    kept: BinaryHeap<Reverse<Kept>>,
+    per_client: HashMap<u16, VecDeque<Kept>>,
+    over: Vec<u16>,
+    evicted: IdRanges,
+    pinned: HashMap<u32, u16>,
+    reported: Option<Vec<u32>>,
+}
+
+/// A transaction in the window. `key` orders it: its timestamp for `Age`, and when it was kept otherwise.
+/// Ids can come in any order, so they're no use for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Kept {
+    pub key: u64,
+    pub tx: u32,
+    pub client: u16,
+}
+
+impl Retention {
+    pub fn new(policy: RetentionPolicy) -> Retention {
+        Retention {
+            policy,
+            clock: 0,
+            next: 0,
+            kept: BinaryHeap::new(),
+            per_client: HashMap::new(),
+            over: Vec::new(),
+            evicted: IdRanges::default(),
+            pinned: HashMap::new(),
+            reported: None,
+        }
+    }
+
+    /// Moves the clock on to a transaction's timestamp, if it has one and it's newer.
+    pub fn tick(&mut self, timestamp: Option<u64>) {
+        self.clock = self.clock.max(timestamp.unwrap_or_default());
+    }
+
+    /// Starts the window for a transaction just recorded in the history.
+    pub fn keep(&mut self, tx: u32, client: u16) {
+        let key = match self.policy {
+            RetentionPolicy::Forever => return,
+            RetentionPolicy::PerClient(_) | RetentionPolicy::Transactions(_) => {
+                self.next += 1;
+                self.next
+            }
+            RetentionPolicy::Age(_) => self.clock,
+        };
+        self.push(Kept { key, tx, client });
+    }
+
+    fn push(&mut self, kept: Kept) {
+        match self.policy {
+            RetentionPolicy::PerClient(n) => {
+                let queue = self.per_client.entry(kept.client).or_default();
+                queue.push_back(kept);
+                if queue.len() > n {
+                    self.over.push(kept.client);
+                }
+            }
+            _ => self.kept.push(Reverse(kept)),
+        }
+    }
+
+    /// Takes every transaction that's left the window out of it, returning them to be evicted or pinned.
+    pub fn expired(&mut self) -> Vec<Kept> {
+        let mut expired = Vec::new();
+        match self.policy {
+            RetentionPolicy::Forever => (),
+            // only a client just kept for can be over, so the others aren't looked at.
+            RetentionPolicy::PerClient(n) => {
+                for client in self.over.drain(..) {
+                    if let Some(queue) = self.per_client.get_mut(&client) {
+                        while queue.len() > n {
+                            expired.extend(queue.pop_front());
+                        }
+                        if queue.is_empty() {
+                            self.per_client.remove(&client);
+                        }
+                    }
+                }
+            }
+            RetentionPolicy::Transactions(n) => {
+                while let Some(Reverse(kept)) = self.kept.peek() {
+                    if self.next - kept.key < n as u64 {
+                        break;
+                    }
+                    expired.extend(self.kept.pop().map(|Reverse(kept)| kept));
+                }
+            }
+            RetentionPolicy::Age(secs) => {
+                while let Some(Reverse(kept)) = self.kept.peek() {
+                    if self.clock.saturating_sub(kept.key) <= secs {
+                        break;
+                    }
+                    expired.extend(self.kept.pop().map(|Reverse(kept)| kept));
+                }
+            }
+        }
+        expired
+    }
+
+    /// Remembers the id of a transaction evicted from the history.
+    pub fn evict(&mut self, tx: u32) {
+        self.evicted.insert(tx);
+        if let Some(reported) = self.reported.as_mut() {
+            reported.push(tx);
+        }
+    }
+
+    /// Starts keeping the ids evicted from here on, to be taken with `take_evicted`.
+    pub fn report(&mut self) {
+        self.reported.get_or_insert_with(Vec::new);
+    }
+
+    /// Takes the ids evicted since last time, once there are at least `at_least` of them.
+    pub fn take_evicted(&mut self, at_least: usize) -> Option<Vec<u32>> {
+        self.reported
+            .as_mut()
+            .filter(|reported| !reported.is_empty() && reported.len() >= at_least)
+            .map(std::mem::take)
+    }
+
+    pub fn is_evicted(&self, tx: u32) -> bool {
+        self.evicted.contains(tx)
+    }
+
+    /// Holds on to a transaction that left the window while disputed.
+    pub fn pin(&mut self, kept: Kept) {
+        self.pinned.insert(kept.tx, kept.client);
+    }
+
+    /// Lets go of a pinned transaction, returning whether it was.
+    pub fn unpin(&mut self, tx: u32) -> bool {
+        self.pinned.remove(&tx).is_some()
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    pub fn evicted(&self) -> &IdRanges {
+        &self.evicted
+    }
+
+    /// Transactions that left the window while disputed, and their client, in no particular order.
+    pub fn pinned(&self) -> impl Iterator<Item = (u32, u16)> + '_ {
+        self.pinned.iter().map(|(tx, client)| (*tx, *client))
+    }
+
+    /// Every transaction in the window, pinned ones aside, in no particular order.
+    pub fn kept(&self) -> impl Iterator<Item = Kept> + '_ {
+        self.kept
+            .iter()
+            .map(|Reverse(kept)| *kept)
+            .chain(self.per_client.values().flatten().copied())
+    }
+
+    /// Puts back what an earlier run remembered, replacing the window its restored transactions were kept in.
+    /// Only those transactions are replaced, so several files can be restored into one window, eg a shard's journal.
+    pub fn restore(
+        &mut self,
+        clock: u64,
+        evicted: &IdRanges,
+        pinned: &[(u32, u16)],
+        mut kept: Vec<Kept>,
+    ) {
+        self.clock = self.clock.max(clock);
+        self.evicted.extend(evicted);
+        self.pinned.extend(pinned.iter().copied());
+
+        let restored: HashSet<u32> = pinned
+            .iter()
+            .map(|(tx, _)| *tx)
+            .chain(kept.iter().map(|kept| kept.tx))
+            .collect();
+        self.kept
+            .retain(|Reverse(kept)| !restored.contains(&kept.tx));
+        for queue in self.per_client.values_mut() {
+            queue.retain(|kept| !restored.contains(&kept.tx));
+        }
+        self.per_client.retain(|_, queue| !queue.is_empty());
+
+        kept.sort_unstable();
+        for kept in kept {
+            self.next = self.next.max(kept.key);
+            self.push(kept);
+        }
+    }
+}
+
+/// A set of transaction ids kept as ranges, so ids that mostly run in order, as they're expected to, take
+/// a few bytes a run rather than per id. Scattered ids cost a range each.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdRanges {
+    ranges: BTreeMap<u32, u32>, // first id to last, inclusive.
+}
+
+impl IdRanges {
+    pub fn contains(&self, id: u32) -> bool {
+        self.ranges
+            .range(..=id)
+            .next_back()
+            .is_some_and(|(_, last)| id <= *last)
+    }
+
+    pub fn insert(&mut self, id: u32) {
+        self.insert_range(id, id);
+    }
+
+    /// Adds every id in `other`.
+    pub fn extend(&mut self, other: &IdRanges) {
+        for (first, last) in other.ranges() {
+            self.insert_range(first, last);
+        }
+    }
+
+    /// The ranges in order, as first and last id.
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().map(|(first, last)| (*first, *last))
+    }
+
+    /// Builds a set from ranges, eg those `ranges()` gave.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (u32, u32)>) -> IdRanges {
+        let mut ids = IdRanges::default();
+        for (first, last) in ranges {
+            ids.insert_range(first, last);
+        }
+        ids
+    }
+
+    /// Adds the ids from `first` to `last`, joining up any ranges they overlap or touch.
+    fn insert_range(&mut self, first: u32, last: u32) {
+        if first > last {
+            return;
+        }
+        let overlapping: Vec<(u32, u32)> = self
+            .ranges
+            .range(..=last.saturating_add(1))
+            .rev()
+            .take_while(|(_, end)| **end >= first.saturating_sub(1))
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        let mut first = first;
+        let mut last = last;
+        for (start, end) in overlapping {
+            self.ranges.remove(&start);
+            first = first.min(start);
+            last = last.max(end);
+        }
+        self.ranges.insert(first, last);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_ranges_should_join_neighbouring_ids() {
+        let mut ids = IdRanges::default();
+        for id in [1, 2, 3, 7, 5, 6, 10, u32::MAX, 0] {
+            ids.insert(id);
+        }
+
+        assert_eq!(
+            ids.ranges().collect::<Vec<_>>(),
+            vec![(0, 3), (5, 7), (10, 10), (u32::MAX, u32::MAX)]
+        );
+        assert!(ids.contains(6));
+        assert!(!ids.contains(4));
+        assert!(!ids.contains(11));
+
+        let restored = IdRanges::from_ranges([(4, 4), (8, 9), (0, 3), (5, 7), (10, 10)]);
+        assert_eq!(restored.ranges().collect::<Vec<_>>(), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn retention_should_expire_per_policy() {
+        let expired = |retention: &mut Retention| {
+            let mut txs: Vec<u32> = retention.expired().iter().map(|k| k.tx).collect();
+            txs.sort_unstable();
+            txs
+        };
+
+        let mut retention = Retention::new(RetentionPolicy::PerClient(2));
+        for (tx, client) in [(1, 1), (2, 2), (3, 1), (4, 1), (5, 2)] {
+            retention.keep(tx, client);
+        }
+        assert_eq!(expired(&mut retention), vec![1]);
+
+        // in the order they arrive, whatever their ids.
+        let mut retention = Retention::new(RetentionPolicy::Transactions(3));
+        for tx in [5, 1, 7, 6] {
+            retention.keep(tx, 1);
+        }
+        assert_eq!(expired(&mut retention), vec![5]);
+        retention.keep(4_000_000_000, 1);
+        assert_eq!(expired(&mut retention), vec![1]);
+
+        let mut retention = Retention::new(RetentionPolicy::Age(60));
+        retention.tick(Some(1000));
+        retention.keep(1, 1);
+        retention.tick(Some(1030));
+        retention.keep(2, 1);
+        retention.keep(3, 1);
+        // no timestamp is as old as the newest seen, and time doesn't go backwards.
+        retention.tick(None);
+        retention.tick(Some(900));
+        assert!(expired(&mut retention).is_empty());
+        retention.tick(Some(1061));
+        assert_eq!(expired(&mut retention), vec![1]);
+
+        let mut retention = Retention::new(RetentionPolicy::Forever);
+        retention.keep(1, 1);
+        assert_eq!(retention.kept().count(), 0);
+    }
+
+    #[test]
+    fn retention_should_restore_several_files_into_one_window() {
+        let mut retention = Retention::new(RetentionPolicy::PerClient(1));
+        let kept = |key, tx, client| Kept { key, tx, client };
+        // restoring the history keeps every transaction, then each file's window replaces its own.
+        for (tx, client) in [(1, 1), (2, 1), (3, 2), (4, 2)] {
+            retention.keep(tx, client);
+        }
+        retention.restore(0, &IdRanges::default(), &[(1, 1)], vec![kept(2, 2, 1)]);
+        retention.restore(
+            0,
+            &IdRanges::default(),
+            &[],
+            vec![kept(3, 3, 2), kept(4, 4, 2)],
+        );
+
+        let mut txs: Vec<u32> = retention.kept().map(|k| k.tx).collect();
+        txs.sort_unstable();
+        assert_eq!(txs, vec![2, 3, 4]);
+        assert_eq!(retention.pinned().collect::<Vec<_>>(), vec![(1, 1)]);
+        assert_eq!(
+            retention.expired().iter().map(|k| k.tx).collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+}
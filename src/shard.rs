@@ -0,0 +1,780 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::client_accounts::{AccountSnapshot, ClientAccounts, Elsewhere, Handoff, Routed};
+use crate::consumer::{self, Rejected};
+use crate::error::EngineError;
+use crate::producer::Row;
+use crate::retention::IdRanges;
+use crate::snapshot;
+use crate::transaction::{Transaction, TransactionType};
+
+/// Called from the shard tasks for every row that was ignored.
+pub type RejectHandler = Arc<dyn Fn(Rejected) + Send + Sync>;
+
+/// Evicted ids a shard collects before passing them back to the router.
+const REPORT_EVERY: usize = 1024;
+
+/// Rows the router holds for a shard before sending them on together.
+const BATCH: usize = 128;
+
+enum ShardMessage {
+    /// Rows that can't leave anything for a client on another shard, in the order they were read.
+    Batch(Vec<(Row, Route)>),
+    /// Answered with what the row left for a client on another shard.
+    Apply(Row, Route, oneshot::Sender<Option<Handoff>>),
+    /// What a row on another shard left for one of this shard's clients.
+    Receive(Handoff),
+    /// Answered once everything before it is applied, with whether the client's account takes deposits.
+    Accepts(u16, oneshot::Sender<bool>),
+    Snapshot(oneshot::Sender<Vec<AccountSnapshot>>),
+    Persist(PathBuf, oneshot::Sender<Result<(), EngineError>>),
+    /// Answered once everything before it is applied, with the claims the shard didn't accept.
+    Sync(oneshot::Sender<Vec<u32>>),
+}
+
+/// What the router worked out about a row.
+///
+/// `claimed`: the row is the first to use the id, so the router has put it down to the row's client.
+/// `routed`: what the row's shard is told.
+#[derive(Debug, Default, Clone, Copy)]
+struct Route {
+    claimed: bool,
+    routed: Routed,
+}
+
+/// An N-way sharded engine. Each shard owns the `ClientAccounts` for its clients on its own task.
+///
+/// Rows are routed on `client % N` over a bounded channel per shard, so every client's transactions
+/// arrive at one shard in the order they were read and backpressure is kept. They're sent in batches of `BATCH`,
+/// and a shard's batch is sent early whenever anything else is sent to it, so nothing overtakes the rows before it.
+/// Rows that couldn't be parsed have no client and go to the first shard just to be reported.
+/// Snapshot requests queue behind the rows already routed, so they see everything sent before them.
+/// Each shard is handed its own `ClientAccounts`, with the history store for its clients.
+///
+/// Transaction ids are unique across clients, but a shard only sees its own clients' history.
+/// With more than one shard the router keeps the client that first used each deposit, withdrawal and transfer id,
+/// and rows reusing an id from another shard are routed marked as such, so their shard rejects them just as a single
+/// shard would, opening the account all the same. A claim only stands if its shard accepts the row: before judging
+/// another client's row by it, the router waits for that shard to catch up and drops the claims it didn't accept.
+/// Ids the `RetentionPolicy` evicts are passed back by the shards and kept as ranges, so `owners` is as bounded as the history.
+///
+/// A transfer to a client on another shard is applied by the sender's shard, once the recipient's shard has said
+/// whether its account takes deposits, and the credit it hands back is passed on to the recipient's shard before
+/// anything else is sent there. Disputes on such a transfer hand the recipient's copy on the same way, so the
+/// router waits for each. Every row is applied just as it would be with one shard.
+pub struct Shards {
+    senders: Vec<Sender<ShardMessage>>,
+    /// rows routed to each shard that haven't been sent yet.
+    batches: Vec<Vec<(Row, Route)>>,
+    handles: Vec<JoinHandle<ClientAccounts>>,
+    owners: HashMap<u32, u16>,
+    evicted: IdRanges,
+    reported: UnboundedReceiver<Vec<u32>>,
+    /// transfers to a client on another shard, whose disputes are handed on to it.
+    handed: HashSet<u32>,
+}
+
+impl Shards {
+    /// Starts a task per `ClientAccounts`, which should only hold clients routed to it by `shard_for`.
+    /// `buffer`: rows a shard's channel holds, in batches, before the router waits for it.
+    /// Fails if the owners of restored transactions can't be read back.
+    pub fn spawn(
+        mut shards: Vec<ClientAccounts>,
+        buffer: usize,
+        on_reject: RejectHandler,
+    ) -> Result<Shards, EngineError> {
+        assert!(!shards.is_empty(), "at least one shard is needed");
+
+        let count = shards.len();
+        let mut owners = HashMap::new();
+        let mut evicted = IdRanges::default();
+        let mut handed = HashSet::new();
+        if count > 1 {
+            for clients in shards.iter_mut() {
+                clients.for_each_record(|tx, record, _| {
+                    owners.insert(tx, record.client());
+                    if record
+                        .to()
+                        .is_some_and(|to| shard_for(to, count) != shard_for(record.client(), count))
+                    {
+                        handed.insert(tx);
+                    }
+                    Ok(())
+                })?;
+                evicted.extend(clients.retention().evicted());
+                clients.report_evicted();
+            }
+        }
+
+        let (report, reported) = mpsc::unbounded_channel();
+        let (senders, handles) = shards
+            .into_iter()
+            .map(|clients| {
+                let (tx, rx) = mpsc::channel((buffer / BATCH).max(1));
+                let shard = run(clients, rx, on_reject.clone(), report.clone());
+                (tx, tokio::spawn(shard))
+            })
+            .unzip();
+
+        Ok(Shards {
+            batches: (0..count).map(|_| Vec::with_capacity(BATCH)).collect(),
+            senders,
+            handles,
+            owners,
+            evicted,
+            reported,
+            handed,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Routes a row to the shard owning its client, waiting if that shard is backed up,
+    /// or until it's applied if it may leave something for a client on another shard.
+    pub async fn apply(&mut self, row: Row) -> Result<(), EngineError> {
+        let shards = self.senders.len();
+        let (shard, transfer_to, hands) = match &row.record {
+            Ok(transaction) => {
+                let shard = shard_for(transaction.client(), shards);
+                let transfer_to = match transaction.typ() {
+                    TransactionType::Transfer => transaction
+                        .to()
+                        .filter(|to| shard_for(*to, shards) != shard),
+                    _ => None,
+                };
+                let hands = match transaction.typ() {
+                    TransactionType::Dispute
+                    | TransactionType::Resolve
+                    | TransactionType::Chargeback => self.handed.contains(&transaction.tx()),
+                    _ => transfer_to.is_some(),
+                };
+                (shard, transfer_to, hands)
+            }
+            Err(_) => (0, None, false),
+        };
+
+        let mut route = self.route(&row).await?;
+        if let Some(to) = transfer_to {
+            route.routed.recipient_accepts = Some(self.accepts(to).await?);
+        }
+        if !hands {
+            self.batches[shard].push((row, route));
+            if self.batches[shard].len() >= BATCH {
+                self.send_batch(shard).await?;
+            }
+            return Ok(());
+        }
+
+        let tx = row.record.as_ref().map(Transaction::tx).ok();
+        let (reply, handoff) = oneshot::channel();
+        self.send(shard, ShardMessage::Apply(row, route, reply))
+            .await?;
+        if let Some(handoff) = handoff.await.map_err(|_| EngineError::ShardFailed(shard))? {
+            if transfer_to.is_some() {
+                self.handed.extend(tx);
+            }
+            let to = shard_for(handoff.to(), shards);
+            self.send(to, ShardMessage::Receive(handoff)).await?;
+        }
+        Ok(())
+    }
+
+    /// Asks the shard owning a client, once it's caught up, whether the client's account takes deposits.
+    async fn accepts(&mut self, client: u16) -> Result<bool, EngineError> {
+        let shard = shard_for(client, self.senders.len());
+        let (reply, accepts) = oneshot::channel();
+        self.send(shard, ShardMessage::Accepts(client, reply))
+            .await?;
+        accepts.await.map_err(|_| EngineError::ShardFailed(shard))
+    }
+
+    /// Sends a message to a shard after the rows held for it.
+    async fn send(&mut self, shard: usize, message: ShardMessage) -> Result<(), EngineError> {
+        self.send_batch(shard).await?;
+        self.senders[shard]
+            .send(message)
+            .await
+            .map_err(|_| EngineError::ShardFailed(shard))
+    }
+
+    /// Sends the rows held for a shard, if there are any.
+    async fn send_batch(&mut self, shard: usize) -> Result<(), EngineError> {
+        if self.batches[shard].is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH));
+        self.senders[shard]
+            .send(ShardMessage::Batch(batch))
+            .await
+            .map_err(|_| EngineError::ShardFailed(shard))
+    }
+
+    /// Sends every shard the rows held for it.
+    async fn send_batches(&mut self) -> Result<(), EngineError> {
+        for shard in 0..self.senders.len() {
+            self.send_batch(shard).await?;
+        }
+        Ok(())
+    }
+
+    /// Works out what a row's shard needs to know about its transaction id, claiming the id if the row is the first to use it.
+    /// A single shard already sees every client's history, so there's nothing to do.
+    async fn route(&mut self, row: &Row) -> Result<Route, EngineError> {
+        self.forget();
+        let shards = self.senders.len();
+        let transaction = match &row.record {
+            Ok(transaction) if shards > 1 => transaction,
+            _ => return Ok(Route::default()),
+        };
+        let claims = match transaction.typ() {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer => {
+                true
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                false
+            }
+            _ => return Ok(Route::default()),
+        };
+        let (client, tx) = (transaction.client(), transaction.tx());
+        let shard = shard_for(client, shards);
+
+        // the claim may not have stood, or its transaction may have been evicted since, which only its shard knows.
+        let owner = self.owners.get(&tx).map(|owner| shard_for(*owner, shards));
+        if let Some(owner) = owner.filter(|owner| *owner != shard) {
+            self.sync(owner).await?;
+        }
+
+        let elsewhere = |elsewhere| Route {
+            claimed: false,
+            routed: Routed {
+                elsewhere: Some(elsewhere),
+                ..Routed::default()
+            },
+        };
+        if self.evicted.contains(tx) {
+            return Ok(elsewhere(Elsewhere::Evicted));
+        }
+        Ok(match self.owners.entry(tx) {
+            Entry::Occupied(owner) if shard_for(*owner.get(), shards) != shard => {
+                elsewhere(Elsewhere::Owned)
+            }
+            Entry::Vacant(owner) if claims => {
+                owner.insert(client);
+                Route {
+                    claimed: true,
+                    ..Route::default()
+                }
+            }
+            _ => Route::default(),
+        })
+    }
+
+    /// Waits for a shard to apply everything routed to it, then drops the claims it didn't accept
+    /// and the ids it's evicted.
+    async fn sync(&mut self, shard: usize) -> Result<(), EngineError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(shard, ShardMessage::Sync(tx)).await?;
+        let unclaimed = rx.await.map_err(|_| EngineError::ShardFailed(shard))?;
+
+        let shards = self.senders.len();
+        for tx in unclaimed {
+            if let Entry::Occupied(owner) = self.owners.entry(tx) {
+                if shard_for(*owner.get(), shards) == shard {
+                    owner.remove();
+                }
+            }
+        }
+        self.forget();
+        Ok(())
+    }
+
+    /// Waits for every shard to apply everything routed to it, so every reject before now has been reported.
+    pub async fn catch_up(&mut self) -> Result<(), EngineError> {
+        for shard in 0..self.senders.len() {
+            self.sync(shard).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the ids the shards have evicted from `owners` to `evicted`.
+    fn forget(&mut self) {
+        while let Ok(evicted) = self.reported.try_recv() {
+            for tx in evicted {
+                self.owners.remove(&tx);
+                self.handed.remove(&tx);
+                self.evicted.insert(tx);
+            }
+        }
+    }
+
+    /// Takes a snapshot of every account across all shards without stopping them, in client then asset order.
+    pub async fn snapshot(&mut self) -> Result<Vec<AccountSnapshot>, EngineError> {
+        self.send_batches().await?;
+        let mut replies = Vec::with_capacity(self.senders.len());
+        for (shard, sender) in self.senders.iter().enumerate() {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(ShardMessage::Snapshot(tx))
+                .await
+                .map_err(|_| EngineError::ShardFailed(shard))?;
+            replies.push(rx);
+        }
+
+        let mut snapshots = Vec::new();
+        for (shard, reply) in replies.into_iter().enumerate() {
+            snapshots.extend(reply.await.map_err(|_| EngineError::ShardFailed(shard))?);
+        }
+        snapshots.sort_unstable_by_key(|s| (s.client, s.asset));
+
+        Ok(snapshots)
+    }
+
+    /// Has each shard write its complete state to its own file, as a `snapshot` of just its clients.
+    /// Like `snapshot`, every shard saves its state as of the same point in the stream.
+    pub async fn persist(&mut self, paths: Vec<PathBuf>) -> Result<(), EngineError> {
+        assert_eq!(
+            paths.len(),
+            self.senders.len(),
+            "a path is needed per shard"
+        );
+        self.send_batches().await?;
+
+        let mut replies = Vec::with_capacity(self.senders.len());
+        for (shard, (sender, path)) in self.senders.iter().zip(paths).enumerate() {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(ShardMessage::Persist(path, tx))
+                .await
+                .map_err(|_| EngineError::ShardFailed(shard))?;
+            replies.push(rx);
+        }
+
+        for (shard, reply) in replies.into_iter().enumerate() {
+            reply.await.map_err(|_| EngineError::ShardFailed(shard))??;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every shard to finish what's been routed to it and hands back their accounts, in shard order.
+    pub async fn join(mut self) -> Result<Vec<ClientAccounts>, EngineError> {
+        self.send_batches().await?;
+        drop(self.senders);
+
+        let mut shards = Vec::with_capacity(self.handles.len());
+        for (shard, handle) in self.handles.into_iter().enumerate() {
+            shards.push(handle.await.map_err(|_| EngineError::ShardFailed(shard))?);
+        }
+
+        Ok(shards)
+    }
+}
+
+/// The shard owning a client's accounts, out of `count` shards.
+pub fn shard_for(client: u16, count: usize) -> usize {
+    client as usize % count
+}
+
+/// Applies a transaction to the shard owning its client, as routing it through `Shards` would.
+/// For replaying a journal, where every shard is to hand so another shard's use of the id is looked up rather than tracked,
+/// and what's left for a client on another shard is handed straight to it.
+pub fn apply_to(
+    shards: &mut [ClientAccounts],
+    transaction: Transaction,
+) -> Result<(), EngineError> {
+    let count = shards.len();
+    let shard = shard_for(transaction.client(), count);
+    let mut routed = Routed::default();
+    if matches!(
+        transaction.typ(),
+        TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+    ) {
+        for (_, clients) in shards
+            .iter_mut()
+            .enumerate()
+            .filter(|(other, _)| *other != shard)
+        {
+            routed.elsewhere = clients.used(transaction.tx())?;
+            if routed.elsewhere.is_some() {
+                break;
+            }
+        }
+    }
+    if let Some(to) = transaction.to().filter(|to| {
+        transaction.typ() == &TransactionType::Transfer && shard_for(*to, count) != shard
+    }) {
+        routed.recipient_accepts = Some(shards[shard_for(to, count)].accepts(to));
+    }
+
+    let result = shards[shard].update_routed(transaction, routed);
+    if let Some(handoff) = shards[shard].take_handoff() {
+        shards[shard_for(handoff.to(), count)].receive(handoff);
+    }
+    result
+}
+
+/// `report`: where to send the ids the shard evicts, for the router to forget.
+async fn run(
+    mut clients: ClientAccounts,
+    mut rx: mpsc::Receiver<ShardMessage>,
+    on_reject: RejectHandler,
+    report: UnboundedSender<Vec<u32>>,
+) -> ClientAccounts {
+    // ids the router claimed for rows this shard rejected, until it next asks.
+    let mut unclaimed = Vec::new();
+    while let Some(message) = rx.recv().await {
+        match message {
+            ShardMessage::Batch(batch) => {
+                for (row, route) in batch {
+                    apply(
+                        &mut clients,
+                        row,
+                        route,
+                        &mut unclaimed,
+                        &on_reject,
+                        &report,
+                    );
+                    let handoff = clients.take_handoff();
+                    debug_assert!(handoff.is_none(), "the router asks for every handoff");
+                }
+            }
+            ShardMessage::Apply(row, route, reply) => {
+                apply(
+                    &mut clients,
+                    row,
+                    route,
+                    &mut unclaimed,
+                    &on_reject,
+                    &report,
+                );
+                let _ = reply.send(clients.take_handoff());
+            }
+            ShardMessage::Receive(handoff) => clients.receive(handoff),
+            ShardMessage::Accepts(client, reply) => {
+                let _ = reply.send(clients.accepts(client));
+            }
+            ShardMessage::Sync(reply) => {
+                if let Some(evicted) = clients.take_evicted(1) {
+                    let _ = report.send(evicted);
+                }
+                // a later row may have used the id after all. If the history can't say, the claim stands.
+                unclaimed.retain(|tx| matches!(clients.used(*tx), Ok(None)));
+                let _ = reply.send(std::mem::take(&mut unclaimed));
+            }
+            ShardMessage::Snapshot(reply) => {
+                let _ = reply.send(clients.snapshot()); // the requester may have given up.
+            }
+            ShardMessage::Persist(path, reply) => {
+                let _ = reply.send(persist(&mut clients, path));
+            }
+        }
+    }
+
+    clients
+}
+
+/// Applies a row on a shard, noting its claim if it was rejected and passing back what's been evicted.
+fn apply(
+    clients: &mut ClientAccounts,
+    row: Row,
+    route: Route,
+    unclaimed: &mut Vec<u32>,
+    on_reject: &RejectHandler,
+    report: &UnboundedSender<Vec<u32>>,
+) {
+    let claim = row.record.as_ref().ok().map(Transaction::tx);
+    if let Err(rejected) = consumer::apply_row(clients, row, route.routed) {
+        unclaimed.extend(claim.filter(|_| route.claimed));
+        on_reject(rejected);
+    }
+    if let Some(evicted) = clients.take_evicted(REPORT_EVERY) {
+        let _ = report.send(evicted); // the router only goes once the shards have.
+    }
+}
+
+/// Writes a shard's complete state to its own file, as a `snapshot` of just its clients, and syncs it to disk.
+pub(crate) fn persist(clients: &mut ClientAccounts, path: PathBuf) -> Result<(), EngineError> {
+    let file = File::create(path)?;
+    snapshot::write_snapshot(std::slice::from_mut(clients), BufWriter::new(&file))?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use crate::config::{EngineConfig, RetentionPolicy};
+    use crate::transaction::{Transaction, TransactionType};
+
+    use super::*;
+
+    /// A row for a transaction. Only deposits, withdrawals and transfers get the amount, the rest cover all of theirs.
+    fn row(line: u64, typ: TransactionType, client: u16, tx: u32, amount: Decimal) -> Row {
+        let builder = match typ {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer => {
+                Transaction::builder(typ, client, tx).amount(amount)
+            }
+            _ => Transaction::builder(typ, client, tx),
+        };
+        Row {
+            source: Arc::new("txs.csv".into()),
+            input: 0,
+            line,
+            offset: 0,
+            raw: None,
+            record: Ok(builder.build()),
+        }
+    }
+
+    #[tokio::test]
+    async fn shards_should_keep_per_client_order_and_merge() -> Result<(), EngineError> {
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let on_reject: RejectHandler = {
+            let rejected = rejected.clone();
+            Arc::new(move |r: Rejected| rejected.lock().unwrap().push((r.line, r.error.code())))
+        };
+        let clients = (0..4).map(|_| ClientAccounts::new()).collect();
+        let mut shards = Shards::spawn(clients, 8, on_reject)?;
+        assert_eq!(shards.len(), 4);
+
+        let mut line = 1;
+        for client in 0..10 {
+            for tx in 0..3 {
+                line += 1;
+                let id = client as u32 * 10 + tx;
+                shards
+                    .apply(row(line, TransactionType::Deposit, client, id, dec!(1.0)))
+                    .await?;
+            }
+            // only succeeds if the deposits were applied first.
+            line += 1;
+            let id = 1000 + client as u32;
+            shards
+                .apply(row(
+                    line,
+                    TransactionType::Withdrawal,
+                    client,
+                    id,
+                    dec!(2.0),
+                ))
+                .await?;
+        }
+
+        let mut snapshots = shards.snapshot().await?;
+        snapshots.sort_by_key(|s| s.client);
+        assert_eq!(snapshots.len(), 10);
+        assert!(snapshots.iter().all(|s| s.total == dec!(1.0)));
+
+        // over-withdrawing is rejected through the handler.
+        shards
+            .apply(row(100, TransactionType::Withdrawal, 3, 2000, dec!(5.0)))
+            .await?;
+
+        // tx ids are global, even across shards.
+        shards
+            .apply(row(101, TransactionType::Deposit, 2, 0, dec!(5.0)))
+            .await?;
+        shards
+            .apply(row(102, TransactionType::Dispute, 5, 10, Decimal::ZERO))
+            .await?;
+
+        // transfers go to clients on other shards too.
+        let transfer = |line, to| {
+            let mut row = row(
+                line,
+                TransactionType::Transfer,
+                1,
+                3000 + to as u32,
+                dec!(0.5),
+            );
+            row.record = row.record.map(|t| Transaction { to: Some(to), ..t });
+            row
+        };
+        shards.apply(transfer(103, 2)).await?;
+        shards.apply(transfer(104, 5)).await?;
+
+        let mut accounts = ClientAccounts::new();
+        for clients in shards.join().await? {
+            accounts.merge(clients);
+        }
+        assert_eq!(accounts.len(), 10);
+        assert_eq!(accounts.get(3).unwrap().total(), dec!(1.0));
+        assert_eq!(accounts.get(1).unwrap().total(), dec!(0.0));
+        assert_eq!(accounts.get(2).unwrap().total(), dec!(1.5));
+        assert_eq!(accounts.get(5).unwrap().held(), dec!(0.0));
+        assert_eq!(accounts.get(5).unwrap().total(), dec!(1.5));
+
+        let mut rejected = rejected.lock().unwrap().clone();
+        rejected.sort();
+        assert_eq!(
+            rejected,
+            vec![
+                (100, "insufficient_funds"),
+                (101, "duplicate_tx"),
+                (102, "wrong_client"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// Runs the rows through `count` shards, returning the accounts and each rejected line with its reason.
+    async fn run_through(
+        count: usize,
+        rows: &[(TransactionType, u16, u32, Decimal, Option<u16>)],
+    ) -> Result<(Vec<AccountSnapshot>, Vec<(u64, &'static str)>), EngineError> {
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let on_reject: RejectHandler = {
+            let rejected = rejected.clone();
+            Arc::new(move |r: Rejected| rejected.lock().unwrap().push((r.line, r.error.code())))
+        };
+        let config = EngineConfig {
+            retention: RetentionPolicy::PerClient(2),
+            ..EngineConfig::default()
+        };
+        let clients = (0..count)
+            .map(|_| ClientAccounts::with_config(config.clone()))
+            .collect();
+        let mut shards = Shards::spawn(clients, 8, on_reject)?;
+        for (line, (typ, client, tx, amount, to)) in rows.iter().enumerate() {
+            let mut row = row(line as u64, typ.clone(), *client, *tx, *amount);
+            row.record = row.record.map(|t| Transaction { to: *to, ..t });
+            shards.apply(row).await?;
+        }
+        let snapshots = shards.snapshot().await?;
+        shards.join().await?;
+
+        let mut rejected = rejected.lock().unwrap().clone();
+        rejected.sort();
+        Ok((snapshots, rejected))
+    }
+
+    #[tokio::test]
+    async fn shards_should_match_a_single_shard() -> Result<(), EngineError> {
+        use TransactionType::*;
+        // disputes, resolves and chargebacks are built without one.
+        let none = Decimal::ZERO;
+        let rows = [
+            (Deposit, 1, 1, dec!(5.0), None),
+            (Dispute, 1, 1, none, None),
+            (Chargeback, 1, 1, none, None),
+            // rejected as client 1 is locked, so the id is still free for client 2.
+            (Deposit, 1, 10, dec!(1.0), None),
+            (Deposit, 2, 10, dec!(2.0), None),
+            (Deposit, 4, 10, dec!(2.0), None),
+            (Deposit, 1, 10, dec!(1.0), None),
+            // client 3 only keeps its last two, so 11 is evicted.
+            (Deposit, 3, 11, dec!(1.0), None),
+            (Deposit, 3, 12, dec!(1.0), None),
+            (Deposit, 3, 13, dec!(1.0), None),
+            (Deposit, 5, 11, dec!(1.0), None),
+            (Dispute, 6, 11, none, None),
+            (Dispute, 7, 12, none, None),
+            // a failed withdrawal still uses its id.
+            (Withdrawal, 2, 20, dec!(9.0), None),
+            (Deposit, 8, 20, dec!(1.0), None),
+            (Dispute, 3, 13, none, None),
+            // transfers between clients on other shards, and disputes on them, as if they were on one.
+            (Deposit, 9, 30, dec!(10.0), None),
+            (Transfer, 9, 31, dec!(4.0), Some(6)),
+            (Dispute, 9, 31, none, None),
+            (Withdrawal, 6, 32, dec!(1.0), None),
+            (Dispute, 6, 31, none, None),
+            (Resolve, 9, 31, none, None),
+            (Withdrawal, 6, 33, dec!(1.0), None),
+            (Transfer, 9, 34, dec!(2.0), Some(1)),
+            (Transfer, 9, 35, dec!(2.0), Some(7)),
+            (Dispute, 9, 35, none, None),
+            (Chargeback, 9, 35, none, None),
+            (Transfer, 9, 36, dec!(1.0), Some(2)),
+            (Transfer, 2, 37, dec!(1.0), Some(11)),
+        ];
+
+        let (expected, expected_rejected) = run_through(1, &rows).await?;
+        assert_eq!(
+            expected.iter().map(|s| s.client).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 11]
+        );
+        assert!(expected[0].locked);
+        let total = |client| expected.iter().find(|s| s.client == client).unwrap().total;
+        assert_eq!(
+            [total(6), total(7), total(9), total(11)],
+            [dec!(3.0), dec!(0.0), dec!(6.0), dec!(1.0)]
+        );
+        assert_eq!(
+            expected_rejected,
+            vec![
+                (3, "account_locked"),
+                (5, "duplicate_tx"),
+                (6, "account_locked"),
+                (10, "duplicate_tx"),
+                (11, "outside_dispute_window"),
+                (12, "wrong_client"),
+                (13, "insufficient_funds"),
+                (14, "duplicate_tx"),
+                (19, "insufficient_funds"),
+                (20, "wrong_client"),
+                (23, "account_locked"),
+                (27, "account_locked"),
+            ]
+        );
+        for count in 2..5 {
+            let (snapshots, rejected) = run_through(count, &rows).await?;
+            assert_eq!(snapshots, expected, "{} shards", count);
+            assert_eq!(rejected, expected_rejected, "{} shards", count);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shards_should_match_a_single_shard_across_batches() -> Result<(), EngineError> {
+        use TransactionType::*;
+        // enough rows to fill several batches per shard, with ids reused across clients and transfers between them.
+        let rows = (0..BATCH as u32 * 8)
+            .map(|i| {
+                let client = (i % 7) as u16 + 1;
+                match i % 5 {
+                    0 | 1 => (Deposit, client, i / 2, dec!(3.0), None),
+                    2 => (Withdrawal, client, i, dec!(2.0), None),
+                    3 => (Transfer, client, i, dec!(1.0), Some(client % 7 + 1)),
+                    _ => (Dispute, client, i - 4, Decimal::ZERO, None),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let (expected, expected_rejected) = run_through(1, &rows).await?;
+        assert!(expected_rejected.iter().any(|(_, r)| *r == "duplicate_tx"));
+        for count in 2..5 {
+            let (snapshots, rejected) = run_through(count, &rows).await?;
+            assert_eq!(snapshots, expected, "{} shards", count);
+            assert_eq!(rejected, expected_rejected, "{} shards", count);
+        }
+
+        Ok(())
+    }
+}
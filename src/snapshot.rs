@@ -0,0 +1,379 @@
+use std::io::{Read, Write};
+
+use rust_decimal::Decimal;
+
+use crate::client_accounts::{AccountSnapshot, ClientAccounts};
+use crate::error::EngineError;
+use crate::history;
+use crate::retention::{IdRanges, Kept};
+use crate::shard;
+use crate::transaction::Asset;
+
+const MAGIC: &[u8; 4] = b"TE3K";
+const VERSION: u16 = 1;
+
+/// Marks the next history entry, or the end of the file.
+const ENTRY: u8 = 1;
+const END: u8 = 0;
+
+/// Writes the complete engine state, every account with its history and open disputes, as a compact binary file.
+///
+/// The format is little endian and versioned, so a file from another version is refused:
+/// - `TE3K` and a u16 version.
+/// - a u32 account count, then each account's client (u16), asset (8 bytes), total (16 byte `Decimal`),
+///   locked flag (u8) and transaction count (u64). A client holding several assets has an entry for each.
+/// - each history entry as `1`, its tx (u32), a disputed flag (u8) and the record, then a `0` to end.
+///   A disputed entry ends with the amount still disputed (16 bytes).
+/// - the dispute window: the newest timestamp seen (u64), a u32 count of evicted id ranges and each
+///   range's first and last id (u32s), a u32 count of pinned transactions and each one's tx (u32) and client (u16),
+///   then a u32 count of transactions in the window and each one's key (u64), tx (u32) and client (u16).
+///
+/// The end marker means a truncated file fails to load rather than quietly losing history.
+pub fn write_snapshot<W: Write>(
+    shards: &mut [ClientAccounts],
+    mut writer: W,
+) -> Result<(), EngineError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    let accounts: Vec<AccountSnapshot> = shards.iter().flat_map(ClientAccounts::snapshot).collect();
+    writer.write_all(&(accounts.len() as u32).to_le_bytes())?;
+    for acct in accounts.iter() {
+        writer.write_all(&acct.client.to_le_bytes())?;
+        writer.write_all(&acct.asset.to_bytes())?;
+        writer.write_all(&acct.total.serialize())?;
+        writer.write_all(&[acct.locked as u8])?;
+        writer.write_all(&acct.transactions.to_le_bytes())?;
+    }
+
+    let mut buf = Vec::with_capacity(6 + history::RECORD_LEN + 16);
+    for clients in shards.iter_mut() {
+        clients.for_each_record(|tx, record, disputed| {
+            buf.clear();
+            buf.push(ENTRY);
+            buf.extend_from_slice(&tx.to_le_bytes());
+            buf.push(disputed.is_some() as u8);
+            history::encode_record(record, &mut buf);
+            if let Some(amount) = disputed {
+                buf.extend_from_slice(&amount.serialize());
+            }
+            writer.write_all(&buf)?;
+            Ok(())
+        })?;
+    }
+    writer.write_all(&[END])?;
+
+    let mut clock = 0;
+    let mut evicted = IdRanges::default();
+    for clients in shards.iter() {
+        clock = clock.max(clients.retention().clock());
+        evicted.extend(clients.retention().evicted());
+    }
+    writer.write_all(&clock.to_le_bytes())?;
+    writer.write_all(&(evicted.len() as u32).to_le_bytes())?;
+    for (first, last) in evicted.ranges() {
+        writer.write_all(&first.to_le_bytes())?;
+        writer.write_all(&last.to_le_bytes())?;
+    }
+
+    let pinned: Vec<(u32, u16)> = shards
+        .iter()
+        .flat_map(|clients| clients.retention().pinned())
+        .collect();
+    writer.write_all(&(pinned.len() as u32).to_le_bytes())?;
+    for (tx, client) in pinned {
+        writer.write_all(&tx.to_le_bytes())?;
+        writer.write_all(&client.to_le_bytes())?;
+    }
+
+    let kept: Vec<Kept> = shards
+        .iter()
+        .flat_map(|clients| clients.retention().kept())
+        .collect();
+    writer.write_all(&(kept.len() as u32).to_le_bytes())?;
+    for kept in kept {
+        writer.write_all(&kept.key.to_le_bytes())?;
+        writer.write_all(&kept.tx.to_le_bytes())?;
+        writer.write_all(&kept.client.to_le_bytes())?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads a file written by `write_snapshot` into empty shards, routing each client to the shard owning it.
+/// The number of shards doesn't have to match the run that wrote it.
+pub fn read_snapshot<R: Read>(
+    mut reader: R,
+    shards: &mut [ClientAccounts],
+) -> Result<(), EngineError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(EngineError::InvalidState("not a snapshot file".into()));
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != VERSION {
+        return Err(EngineError::InvalidState(format!(
+            "unsupported snapshot version {}",
+            version
+        )));
+    }
+
+    let count = u32::from_le_bytes(read_array(&mut reader)?);
+    for _ in 0..count {
+        let client = u16::from_le_bytes(read_array(&mut reader)?);
+        let asset = Asset::from_bytes(read_array(&mut reader)?)
+            .ok_or_else(|| EngineError::InvalidState("invalid asset in snapshot".into()))?;
+        let total = Decimal::deserialize(read_array(&mut reader)?);
+        let [locked] = read_array(&mut reader)?;
+        let transactions = u64::from_le_bytes(read_array(&mut reader)?);
+        shards[shard::shard_for(client, shards.len())].restore_account(&AccountSnapshot {
+            client,
+            asset,
+            available: total,
+            held: Decimal::ZERO,
+            total,
+            locked: locked != 0,
+            open_disputes: 0,
+            transactions,
+        });
+    }
+
+    let mut record = [0; history::RECORD_LEN];
+    loop {
+        match read_array(&mut reader)? {
+            [ENTRY] => (),
+            [END] => break,
+            [marker] => {
+                return Err(EngineError::InvalidState(format!(
+                    "unexpected marker {} in snapshot",
+                    marker
+                )))
+            }
+        }
+
+        let tx = u32::from_le_bytes(read_array(&mut reader)?);
+        let [disputed] = read_array(&mut reader)?;
+        reader.read_exact(&mut record)?;
+        let (record, _) = history::decode_record(&record)?;
+        let disputed = match disputed {
+            0 => None,
+            _ => Some(Decimal::deserialize(read_array(&mut reader)?)),
+        };
+        if let (Some(to), Some(disputed)) = (record.to, disputed) {
+            shards[shard::shard_for(to, shards.len())].restore_received(tx, &record, disputed);
+        }
+        shards[shard::shard_for(record.client(), shards.len())]
+            .restore_record(tx, record, disputed)?;
+    }
+
+    let clock = u64::from_le_bytes(read_array(&mut reader)?);
+    let count = u32::from_le_bytes(read_array(&mut reader)?);
+    let mut ranges = Vec::new();
+    for _ in 0..count {
+        let first = u32::from_le_bytes(read_array(&mut reader)?);
+        let last = u32::from_le_bytes(read_array(&mut reader)?);
+        ranges.push((first, last));
+    }
+    let evicted = IdRanges::from_ranges(ranges);
+
+    let mut pinned = vec![Vec::new(); shards.len()];
+    let count = u32::from_le_bytes(read_array(&mut reader)?);
+    for _ in 0..count {
+        let tx = u32::from_le_bytes(read_array(&mut reader)?);
+        let client = u16::from_le_bytes(read_array(&mut reader)?);
+        pinned[shard::shard_for(client, shards.len())].push((tx, client));
+    }
+
+    let mut kept = vec![Vec::new(); shards.len()];
+    let count = u32::from_le_bytes(read_array(&mut reader)?);
+    for _ in 0..count {
+        let key = u64::from_le_bytes(read_array(&mut reader)?);
+        let tx = u32::from_le_bytes(read_array(&mut reader)?);
+        let client = u16::from_le_bytes(read_array(&mut reader)?);
+        kept[shard::shard_for(client, shards.len())].push(Kept { key, tx, client });
+    }
+
+    for ((clients, pinned), kept) in shards.iter_mut().zip(pinned).zip(kept) {
+        clients.restore_retention(clock, &evicted, &pinned, kept);
+    }
+    Ok(())
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], EngineError> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::config::{EngineConfig, RetentionPolicy};
+    use crate::transaction::{Transaction, TransactionType};
+
+    use super::*;
+
+    fn apply(accts: &mut ClientAccounts, typ: TransactionType, client: u16, tx: u32) {
+        accts
+            .update(
+                Transaction::builder(typ, client, tx)
+                    .amount(dec!(1.25))
+                    .build(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn snapshot_should_restore_the_complete_state() -> Result<(), EngineError> {
+        let mut first = [ClientAccounts::new(), ClientAccounts::new()];
+        apply(&mut first[1], TransactionType::Deposit, 1, 1);
+        apply(&mut first[1], TransactionType::Deposit, 1, 2);
+        apply(&mut first[1], TransactionType::Dispute, 1, 2);
+        apply(&mut first[0], TransactionType::Deposit, 2, 3);
+        apply(&mut first[0], TransactionType::Dispute, 2, 3);
+        apply(&mut first[0], TransactionType::Chargeback, 2, 3);
+        first[1].update(
+            Transaction::builder(TransactionType::Deposit, 1, 4)
+                .amount(dec!(2))
+                .asset("BTC".parse().unwrap())
+                .build(),
+        )?;
+        // only part of it is disputed.
+        apply(&mut first[1], TransactionType::Dispute, 1, 4);
+
+        let mut buf = Vec::new();
+        write_snapshot(&mut first, &mut buf)?;
+
+        let mut second = [ClientAccounts::new()];
+        read_snapshot(&buf[..], &mut second)?;
+
+        let mut expected: Vec<_> = first.iter().flat_map(ClientAccounts::snapshot).collect();
+        let mut restored = second[0].snapshot();
+        expected.sort_by_key(|s| (s.client, s.asset));
+        restored.sort_by_key(|s| (s.client, s.asset));
+        assert_eq!(restored, expected);
+        assert_eq!(
+            second[0].get(1).unwrap().held_of("BTC".parse().unwrap()),
+            dec!(1.25)
+        );
+
+        // late disputes against old transactions still resolve, and old ids are still taken.
+        apply(&mut second[0], TransactionType::Resolve, 1, 2);
+        assert_eq!(second[0].get(1).unwrap().available(), dec!(2.5));
+        assert!(matches!(
+            second[0].update(
+                Transaction::builder(TransactionType::Deposit, 3, 1)
+                    .amount(dec!(1.0))
+                    .build()
+            ),
+            Err(EngineError::DuplicateTx(1))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_should_keep_the_dispute_window() -> Result<(), EngineError> {
+        let accounts = || {
+            ClientAccounts::with_config(EngineConfig {
+                retention: RetentionPolicy::PerClient(1),
+                ..EngineConfig::default()
+            })
+        };
+        let mut first = [accounts()];
+        apply(&mut first[0], TransactionType::Deposit, 1, 1);
+        apply(&mut first[0], TransactionType::Deposit, 1, 2);
+        apply(&mut first[0], TransactionType::Dispute, 1, 2);
+        // 2 leaves the window but is still disputed.
+        apply(&mut first[0], TransactionType::Deposit, 1, 3);
+
+        let mut buf = Vec::new();
+        write_snapshot(&mut first, &mut buf)?;
+        let mut second = [accounts(), accounts()];
+        read_snapshot(&buf[..], &mut second)?;
+        let shard = &mut second[shard::shard_for(1, 2)];
+
+        let outside = |accts: &mut ClientAccounts, typ, tx| {
+            matches!(
+                accts.update(Transaction::builder(typ, 1, tx).build()),
+                Err(EngineError::OutsideDisputeWindow(_))
+            )
+        };
+        assert!(outside(shard, TransactionType::Dispute, 1));
+        assert!(matches!(
+            second[shard::shard_for(2, 2)].update(
+                Transaction::builder(TransactionType::Deposit, 2, 1)
+                    .amount(dec!(1))
+                    .build()
+            ),
+            Err(EngineError::DuplicateTx(1))
+        ));
+
+        let shard = &mut second[shard::shard_for(1, 2)];
+        apply(shard, TransactionType::Resolve, 1, 2);
+        assert!(outside(shard, TransactionType::Dispute, 2));
+        apply(shard, TransactionType::Dispute, 1, 3);
+        apply(shard, TransactionType::Resolve, 1, 3);
+        apply(shard, TransactionType::Deposit, 1, 4);
+        assert!(outside(shard, TransactionType::Dispute, 3));
+        assert_eq!(shard.get(1).unwrap().total(), dec!(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_should_restore_transfers_split_across_shards() -> Result<(), EngineError> {
+        let mut first = [ClientAccounts::new()];
+        apply(&mut first[0], TransactionType::Deposit, 1, 1);
+        first[0].update(
+            Transaction::builder(TransactionType::Transfer, 1, 2)
+                .amount(dec!(1.25))
+                .to(4)
+                .build(),
+        )?;
+        apply(&mut first[0], TransactionType::Dispute, 1, 2);
+        let mut buf = Vec::new();
+        write_snapshot(&mut first, &mut buf)?;
+
+        // clients 1 and 4 are on different shards of 2, but the recipient still holds the disputed funds.
+        let mut shards = [ClientAccounts::new(), ClientAccounts::new()];
+        read_snapshot(&buf[..], &mut shards)?;
+        let (sender, recipient) = (shard::shard_for(1, 2), shard::shard_for(4, 2));
+        assert_ne!(sender, recipient);
+        assert_eq!(shards[recipient].get(4).unwrap().held(), dec!(1.25));
+        shard::apply_to(
+            &mut shards,
+            Transaction::builder(TransactionType::Chargeback, 1, 2).build(),
+        )?;
+        assert_eq!(shards[recipient].get(4).unwrap().total(), dec!(0));
+        assert_eq!(shards[sender].get(1).unwrap().total(), dec!(1.25));
+        assert!(shards.iter().all(|shard| shard.iter().count() == 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_should_reject_bad_and_truncated_files() -> Result<(), EngineError> {
+        let mut accts = [ClientAccounts::new()];
+        apply(&mut accts[0], TransactionType::Deposit, 1, 1);
+        let mut buf = Vec::new();
+        write_snapshot(&mut accts, &mut buf)?;
+
+        let load = |bytes: &[u8]| read_snapshot(bytes, &mut [ClientAccounts::new()]);
+        assert!(load(&buf).is_ok());
+        assert!(load(&buf[..buf.len() - 1]).is_err());
+        assert!(matches!(
+            load(b"PK\x03\x04\x01\x00"),
+            Err(EngineError::InvalidState(_))
+        ));
+
+        let mut newer = buf.clone();
+        newer[4] = 2;
+        assert!(matches!(load(&newer), Err(EngineError::InvalidState(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{Read, Write};
+
+use csv::Trim;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::client_accounts::{AccountSnapshot, ClientAccounts};
+use crate::error::EngineError;
+use crate::shard;
+use crate::transaction::{self, Asset, DisputeState, TransactionHistoryRecord, TransactionType};
+
+/// A row of the accounts csv written at the end of a run. The transaction count is only there if it was written with extras,
+/// and the asset if there was more than one.
+#[derive(Debug, Deserialize)]
+struct AccountRow {
+    id: u16,
+    #[serde(default, deserialize_with = "transaction::deserialize_asset")]
+    asset: Asset,
+    #[serde(deserialize_with = "transaction::deserialize_decimal")]
+    held: Decimal,
+    #[serde(deserialize_with = "transaction::deserialize_decimal")]
+    total: Decimal,
+    locked: bool,
+    #[serde(default)]
+    transactions: u64,
+}
+
+/// A row of a saved history file: every applied transaction and whether it's disputed.
+/// `disputed_amount` is how much is disputed, when it's only part of the transaction, and `state` where it is in its disputes.
+/// Older files don't have them.
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryRow {
+    tx: u32,
+    client: u16,
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(deserialize_with = "transaction::deserialize_decimal")]
+    amount: Decimal,
+    disputed: bool,
+    #[serde(default, deserialize_with = "transaction::deserialize_asset")]
+    asset: Asset,
+    #[serde(default)]
+    to: Option<u16>,
+    #[serde(default, deserialize_with = "transaction::deserialize_amount")]
+    disputed_amount: Option<Decimal>,
+    #[serde(default)]
+    state: Option<String>,
+}
+
+/// Seeds the accounts of each shard from the output of an earlier run, so runs can be chained day to day.
+///
+/// `accounts`: the account csv an earlier run printed.
+/// `history`: the history it saved with `write_history`. Needed to dispute older transactions,
+/// and to restore held funds since those are only known through their disputes.
+///
+/// Each account goes to the shard owning its client. Fails if the files don't agree with each other.
+pub fn restore<A: Read, H: Read>(
+    accounts: A,
+    history: Option<H>,
+    shards: &mut [ClientAccounts],
+) -> Result<(), EngineError> {
+    let mut held = Vec::new();
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(accounts);
+    for row in rdr.deserialize() {
+        let row: AccountRow = row?;
+        shards[shard::shard_for(row.id, shards.len())].restore_account(&AccountSnapshot {
+            client: row.id,
+            asset: row.asset,
+            available: row.total - row.held,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+            open_disputes: 0,
+            transactions: row.transactions,
+        });
+        held.push((row.id, row.asset, row.held));
+    }
+
+    if let Some(history) = history {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(history);
+        for row in rdr.deserialize() {
+            let row: HistoryRow = row?;
+            let record = TransactionHistoryRecord {
+                client: row.client,
+                typ: parse_type(&row.typ)?,
+                amount: row.amount,
+                asset: row.asset,
+                to: row.to,
+                state: match row.state.as_deref() {
+                    None | Some("") => DisputeState::Settled,
+                    Some(state) => state.parse().map_err(EngineError::InvalidState)?,
+                },
+            };
+            let disputed = row
+                .disputed
+                .then(|| row.disputed_amount.unwrap_or(row.amount));
+            if let (Some(to), Some(disputed)) = (record.to, disputed) {
+                shards[shard::shard_for(to, shards.len())]
+                    .restore_received(row.tx, &record, disputed);
+            }
+            shards[shard::shard_for(row.client, shards.len())]
+                .restore_record(row.tx, record, disputed)?;
+        }
+    }
+
+    for (client, asset, expected) in held {
+        let actual = shards[shard::shard_for(client, shards.len())]
+            .get(client)
+            .map_or(Decimal::ZERO, |acct| acct.held_of(asset));
+        if actual != expected {
+            return Err(EngineError::InvalidState(format!(
+                "client {} has {} {} held but its disputed history adds up to {}",
+                client, expected, asset, actual
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the history of every shard as csv, to be restored by the next run.
+pub fn write_history<W: Write>(
+    shards: &mut [ClientAccounts],
+    writer: W,
+) -> Result<(), EngineError> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for clients in shards.iter_mut() {
+        clients.for_each_record(|tx, record, disputed| {
+            wtr.serialize(HistoryRow {
+                tx,
+                client: record.client,
+                typ: type_name(&record.typ).into(),
+                amount: record.amount,
+                disputed: disputed.is_some(),
+                asset: record.asset,
+                to: record.to,
+                disputed_amount: disputed.filter(|amount| *amount != record.amount),
+                state: Some(record.state.to_string()),
+            })?;
+            Ok(())
+        })?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+fn type_name(typ: &TransactionType) -> &'static str {
+    match typ {
+        TransactionType::Deposit => "deposit",
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::FailedWithdrawal => "failed_withdrawal",
+        TransactionType::Transfer => "transfer",
+        // nothing else is ever recorded in the history.
+        _ => "unknown",
+    }
+}
+
+fn parse_type(typ: &str) -> Result<TransactionType, EngineError> {
+    match typ {
+        "deposit" => Ok(TransactionType::Deposit),
+        "withdrawal" => Ok(TransactionType::Withdrawal),
+        "failed_withdrawal" => Ok(TransactionType::FailedWithdrawal),
+        "transfer" => Ok(TransactionType::Transfer),
+        _ => Err(EngineError::InvalidState(format!(
+            "unknown history type {}",
+            typ
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use rust_decimal_macros::dec;
+
+    use crate::transaction::Transaction;
+
+    use super::*;
+
+    fn apply(accts: &mut ClientAccounts, typ: TransactionType, client: u16, tx: u32) {
+        accts
+            .update(
+                Transaction::builder(typ, client, tx)
+                    .amount(dec!(1.5))
+                    .build(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn state_should_chain_from_one_run_to_the_next() -> Result<(), EngineError> {
+        let mut first = [ClientAccounts::new()];
+        apply(&mut first[0], TransactionType::Deposit, 1, 1);
+        apply(&mut first[0], TransactionType::Deposit, 1, 2);
+        apply(&mut first[0], TransactionType::Deposit, 2, 3);
+        apply(&mut first[0], TransactionType::Dispute, 1, 2);
+        apply(&mut first[0], TransactionType::Dispute, 2, 3);
+        apply(&mut first[0], TransactionType::Chargeback, 2, 3);
+        first[0].update(
+            Transaction::builder(TransactionType::Deposit, 1, 4)
+                .amount(dec!(2.0))
+                .build(),
+        )?;
+        apply(&mut first[0], TransactionType::Dispute, 1, 4);
+
+        let mut accounts = Vec::new();
+        first[0].write_csv(&mut accounts)?;
+        let mut history = Vec::new();
+        write_history(&mut first, &mut history)?;
+
+        // restore across two shards this time.
+        let mut second = [ClientAccounts::new(), ClientAccounts::new()];
+        restore(&accounts[..], Some(&history[..]), &mut second)?;
+
+        // the partly disputed deposit only holds the part disputed.
+        let one = second[1].get(1).unwrap();
+        assert_eq!((one.total(), one.held()), (dec!(5.0), dec!(3.0)));
+        let two = second[0].get(2).unwrap();
+        assert_eq!((two.total(), two.locked()), (dec!(0.0), true));
+
+        // the old dispute still resolves and old ids are still taken.
+        apply(&mut second[1], TransactionType::Resolve, 1, 2);
+        assert_eq!(second[1].get(1).unwrap().available(), dec!(3.5));
+        assert!(matches!(
+            second[1].update(
+                Transaction::builder(TransactionType::Deposit, 1, 1)
+                    .amount(dec!(1.0))
+                    .build()
+            ),
+            Err(EngineError::DuplicateTx(1))
+        ));
+        // and so is where each one got to in its disputes.
+        assert!(matches!(
+            second[0].update(Transaction::builder(TransactionType::Dispute, 2, 3).build()),
+            Err(EngineError::DisputeClosed(3, DisputeState::ChargedBack))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn state_should_need_history_for_held_funds() {
+        let accounts = indoc! {"
+            id,available,held,total,locked
+            1,1.00,0.50,1.50,false
+        "};
+
+        let mut shards = [ClientAccounts::new()];
+        let result = restore(accounts.as_bytes(), None::<&[u8]>, &mut shards);
+        assert!(matches!(result, Err(EngineError::InvalidState(_))));
+
+        let history = indoc! {"
+            tx,client,type,amount,disputed
+            4,1,deposit,0.50,true
+        "};
+        let mut shards = [ClientAccounts::new()];
+        restore(accounts.as_bytes(), Some(history.as_bytes()), &mut shards).unwrap();
+        assert_eq!(shards[0].get(1).unwrap().available(), dec!(1.0));
+        // amounts are parsed exactly, never through a float.
+        assert_eq!(shards[0].get(1).unwrap().total().to_string(), "1.50");
+    }
+}
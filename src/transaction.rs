@@ -1,16 +1,29 @@
-use serde::{Deserialize, Deserializer};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 
-/// Enum representing the 5 transaction types.
+use rust_decimal::Decimal;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::config::RedisputePolicy;
+use crate::error::EngineError;
+
+/// Enum representing the 5 transaction types, plus the `unlock` admin type and internal `transfer`s.
 ///
 /// Implements Deserialize so can be used with serde.
 /// Unknown transaction types will deserialize to Unknown which we just ignore.
-#[derive(Debug, Eq, PartialEq)]
+/// `Unlock` reopens an account locked by a chargeback once support staff have reviewed it.
+/// `Transfer` moves funds from the row's client to the client in its `to` column.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Transfer,
     FailedWithdrawal, // shouldn't see a tx again - if it failed it's still a tx that shouldn't occur.
     Unknown(String),
 }
@@ -27,37 +40,359 @@ impl<'de> Deserialize<'de> for TransactionType {
             "dispute" => TransactionType::Dispute,
             "resolve" => TransactionType::Resolve,
             "chargeback" => TransactionType::Chargeback,
+            "unlock" => TransactionType::Unlock,
+            "transfer" => TransactionType::Transfer,
             _ => TransactionType::Unknown(s),
         })
     }
 }
 
+/// An asset or currency code, eg `USD` or `BTC`, of up to 8 ascii characters.
+///
+/// It's kept inline rather than as a String so it's cheap to copy and store with every transaction.
+/// The default, an empty code, is the asset of every row without one, so single asset inputs needn't name it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Asset([u8; Asset::LEN]);
+
+impl Asset {
+    pub const LEN: usize = 8;
+
+    pub fn is_default(&self) -> bool {
+        self.0 == [0; Asset::LEN]
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(Asset::LEN);
+        // only ever built from ascii.
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Asset::LEN] {
+        self.0
+    }
+
+    /// Reads the bytes of `to_bytes` back, checking they could have come from it.
+    pub(crate) fn from_bytes(bytes: [u8; Asset::LEN]) -> Option<Asset> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(Asset::LEN);
+        let valid =
+            bytes[..len].iter().all(u8::is_ascii_graphic) && bytes[len..].iter().all(|b| *b == 0);
+        valid.then_some(Asset(bytes))
+    }
+}
+
+impl FromStr for Asset {
+    type Err = String;
+
+    /// An empty code is the default asset.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() > Asset::LEN || !s.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(format!(
+                "invalid asset {} (expected up to {} ascii letters, digits or symbols)",
+                s,
+                Asset::LEN
+            ));
+        }
+        let mut bytes = [0; Asset::LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Asset(bytes))
+    }
+}
+
+impl Display for Asset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Asset {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 /// Implements a transaction record.
 ///
 /// The `typ` is the type of transaction.
 /// client is a u16 representing the unique client id.
 /// tx is the transaction id which is an unordered number uniquely representing a transaction.
-/// amount is an f32 representing the amount of the transaction. (f32 used assuming USD as it's enough for most of the crypto market cap.)
-#[derive(Debug, Deserialize)]
+/// amount is a Decimal parsed straight from the text so it never passes through binary floating point.
+/// asset is from the optional `asset` (or `currency`) column. Disputes and the like use the asset of the transaction
+/// they name, whatever theirs says.
+/// to is the client a transfer credits, from the optional `to` (or `destination`) column. Nothing else uses it.
+/// timestamp is when it happened, in seconds since the unix epoch, from the optional `timestamp` column.
+/// Only a `RetentionPolicy::Age` dispute window reads it.
+#[derive(Debug, Clone, Deserialize)]
 pub struct Transaction {
     #[serde(alias = "type")]
     pub(crate) typ: TransactionType,
     pub(crate) client: u16,
-    pub tx: u32,
-    pub(crate) amount: Option<f64>,
+    pub(crate) tx: u32,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub(crate) amount: Option<Decimal>,
+    #[serde(default, alias = "currency", deserialize_with = "deserialize_asset")]
+    pub(crate) asset: Asset,
+    #[serde(default, alias = "destination")]
+    pub(crate) to: Option<u16>,
+    #[serde(default)]
+    pub(crate) timestamp: Option<u64>,
 }
 
-/// Record of transaction having been processed stored in a client.
-#[derive(Debug, PartialEq)]
+impl Transaction {
+    /// Starts building a transaction. Anything optional, like the amount, is set on the builder.
+    pub fn builder(typ: TransactionType, client: u16, tx: u32) -> TransactionBuilder {
+        TransactionBuilder::new(typ, client, tx)
+    }
+
+    pub fn typ(&self) -> &TransactionType {
+        &self.typ
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    pub fn asset(&self) -> Asset {
+        self.asset
+    }
+
+    pub fn to(&self) -> Option<u16> {
+        self.to
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+}
+
+/// Builds a `Transaction` for callers feeding the engine directly rather than through csv.
+///
+/// eg: `Transaction::builder(TransactionType::Deposit, 1, 7).amount(dec!(1.5)).build()`
+#[derive(Debug)]
+pub struct TransactionBuilder {
+    transaction: Transaction,
+}
+
+impl TransactionBuilder {
+    pub fn new(typ: TransactionType, client: u16, tx: u32) -> TransactionBuilder {
+        TransactionBuilder {
+            transaction: Transaction {
+                typ,
+                client,
+                tx,
+                amount: None,
+                asset: Asset::default(),
+                to: None,
+                timestamp: None,
+            },
+        }
+    }
+
+    pub fn amount(mut self, amount: Decimal) -> TransactionBuilder {
+        self.transaction.amount = Some(amount);
+        self
+    }
+
+    pub fn asset(mut self, asset: Asset) -> TransactionBuilder {
+        self.transaction.asset = asset;
+        self
+    }
+
+    pub fn to(mut self, client: u16) -> TransactionBuilder {
+        self.transaction.to = Some(client);
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: u64) -> TransactionBuilder {
+        self.transaction.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        self.transaction
+    }
+}
+
+/// Parses the amount column exactly. Empty fields are None, anything that isn't a decimal fails the row.
+pub(crate) fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) if !s.trim().is_empty() => Decimal::from_str_exact(s.trim())
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("invalid amount {}: {}", s, e))),
+        _ => Ok(None),
+    }
+}
+
+/// Parses a required decimal column exactly, rather than letting serde guess it's a float.
+pub(crate) fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_amount(deserializer)?.ok_or_else(|| D::Error::custom("missing amount"))
+}
+
+/// Parses the asset column. Empty fields, or no column at all, are the default asset.
+pub(crate) fn deserialize_asset<'de, D>(deserializer: D) -> Result<Asset, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s.parse().map_err(D::Error::custom),
+        None => Ok(Asset::default()),
+    }
+}
+
+/// Parses a line of json, eg `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`, into a transaction.
+///
+/// The amount can be a string or a number. Numbers are taken as written, so `1.10` keeps its scale
+/// and never passes through a float, the same as a csv field.
+pub(crate) fn from_json(line: &str) -> Result<Transaction, serde_json::Error> {
+    let mut value: Value = serde_json::from_str(line)?;
+    if let Some(amount) = value.get_mut("amount") {
+        if let Value::Number(n) = amount {
+            *amount = Value::String(n.to_string());
+        }
+    }
+    Transaction::deserialize(value)
+}
+
+/// Record of transaction having been processed, with the client that owns it and the asset it moved.
+/// A transfer is owned by the client it came from, and `to` is the client it credited.
+/// `amount` is what's left of it, a chargeback takes off what it reverses. `state` is where it is in its disputes.
+#[derive(Debug, Clone, PartialEq)]
 pub struct TransactionHistoryRecord {
+    pub(crate) client: u16,
     pub(crate) typ: TransactionType,
-    pub(crate) amount: f64,
+    pub(crate) amount: Decimal,
+    pub(crate) asset: Asset,
+    pub(crate) to: Option<u16>,
+    pub(crate) state: DisputeState,
+}
+
+/// Where a transaction is in its dispute lifecycle.
+///
+/// `Settled`: never disputed.
+/// `Disputed`: a dispute is open, for all or part of it.
+/// `Resolved`: the last dispute was resolved.
+/// `ChargedBack`: the last dispute ended in a chargeback.
+///
+/// A transaction only moves between them through `next`, anything else is rejected.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DisputeState {
+    #[default]
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// The state a dispute, resolve or chargeback moves a transaction to, or why it can't.
+    ///
+    /// `closes`: whether a resolve or chargeback settles all that's still disputed. Part of it leaves the dispute open.
+    /// `redispute`: whether a closed dispute can be opened again.
+    pub fn next(
+        self,
+        typ: &TransactionType,
+        closes: bool,
+        redispute: RedisputePolicy,
+        tx: u32,
+    ) -> Result<DisputeState, EngineError> {
+        match (self, typ) {
+            (DisputeState::Disputed, TransactionType::Dispute) => {
+                Err(EngineError::AlreadyDisputed(tx))
+            }
+            (DisputeState::Settled, TransactionType::Dispute) => Ok(DisputeState::Disputed),
+            (DisputeState::Resolved | DisputeState::ChargedBack, TransactionType::Dispute)
+                if redispute.permits(self) =>
+            {
+                Ok(DisputeState::Disputed)
+            }
+            (DisputeState::Disputed, TransactionType::Resolve) if closes => {
+                Ok(DisputeState::Resolved)
+            }
+            (DisputeState::Disputed, TransactionType::Chargeback) if closes => {
+                Ok(DisputeState::ChargedBack)
+            }
+            (DisputeState::Disputed, TransactionType::Resolve | TransactionType::Chargeback) => {
+                Ok(DisputeState::Disputed)
+            }
+            (DisputeState::Settled, _) => Err(EngineError::NotDisputed(tx)),
+            (_, _) => Err(EngineError::DisputeClosed(tx, self)),
+        }
+    }
+}
+
+impl FromStr for DisputeState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "settled" => Ok(DisputeState::Settled),
+            "disputed" => Ok(DisputeState::Disputed),
+            "resolved" => Ok(DisputeState::Resolved),
+            "charged_back" => Ok(DisputeState::ChargedBack),
+            _ => Err(format!("unknown dispute state {}", s)),
+        }
+    }
+}
+
+impl Display for DisputeState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DisputeState::Settled => write!(f, "settled"),
+            DisputeState::Disputed => write!(f, "disputed"),
+            DisputeState::Resolved => write!(f, "resolved"),
+            DisputeState::ChargedBack => write!(f, "charged_back"),
+        }
+    }
+}
+
+impl TransactionHistoryRecord {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn typ(&self) -> &TransactionType {
+        &self.typ
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn asset(&self) -> Asset {
+        self.asset
+    }
+
+    pub fn to(&self) -> Option<u16> {
+        self.to
+    }
+
+    pub fn state(&self) -> DisputeState {
+        self.state
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use csv::Trim::All;
     use indoc::indoc;
+    use rust_decimal_macros::dec;
 
     use super::*;
 
@@ -75,7 +410,7 @@ mod tests {
             assert_eq!(tx.typ, TransactionType::Deposit);
             assert_eq!(tx.client, 1);
             assert_eq!(tx.tx, 1);
-            assert_eq!(tx.amount, Some(1.1111));
+            assert_eq!(tx.amount, Some(dec!(1.1111)));
         }
     }
 
@@ -93,7 +428,7 @@ mod tests {
             assert_eq!(tx.typ, TransactionType::Withdrawal);
             assert_eq!(tx.client, 1);
             assert_eq!(tx.tx, 1);
-            assert_eq!(tx.amount, Some(1.1111));
+            assert_eq!(tx.amount, Some(dec!(1.1111)));
         }
     }
 
@@ -133,6 +468,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn deserialize_unlock_should_succeed() {
+        let csv = indoc!(
+            "type,client,tx,amount
+            unlock,1,1,
+        "
+        );
+        let mut rdr = csv::ReaderBuilder::new().from_reader(csv.as_bytes());
+        for result in rdr.deserialize() {
+            let tx: Transaction = result.unwrap();
+            assert_eq!(tx.typ, TransactionType::Unlock);
+            assert_eq!(tx.client, 1);
+            assert_eq!(tx.amount, None);
+        }
+    }
+
     #[test]
     fn deserialize_unknown_tx_type_should_succeed() {
         let csv = indoc!(
@@ -171,4 +522,183 @@ mod tests {
             assert_eq!(tx.amount, None);
         }
     }
+
+    #[test]
+    fn deserialize_asset_should_default_when_missing() {
+        let csv = indoc!(
+            "type,client,tx,amount,currency
+            deposit,1,1,1.0,USD
+            deposit,1,2,1.0,
+        "
+        );
+        let mut rdr = csv::ReaderBuilder::new().from_reader(csv.as_bytes());
+        let assets: Vec<Asset> = rdr
+            .deserialize()
+            .map(|r: Result<Transaction, _>| r.unwrap().asset)
+            .collect();
+        assert_eq!(assets, vec!["USD".parse().unwrap(), Asset::default()]);
+
+        // rows without the column at all are the default asset too.
+        let tx = from_json(r#"{"type":"deposit","client":1,"tx":2,"amount":"1"}"#).unwrap();
+        assert!(tx.asset.is_default());
+        let tx = from_json(r#"{"type":"deposit","client":1,"tx":2,"amount":"1","asset":"BTC"}"#);
+        assert_eq!(tx.unwrap().asset.to_string(), "BTC");
+
+        assert!("TOOLONGASSET".parse::<Asset>().is_err());
+        assert!("U D".parse::<Asset>().is_err());
+        assert_eq!(
+            Asset::from_bytes(*b"USD\0\0\0\0\0").unwrap().as_str(),
+            "USD"
+        );
+        assert_eq!(Asset::from_bytes(*b"U\0D\0\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn builder_should_set_fields() {
+        let tx = Transaction::builder(TransactionType::Withdrawal, 3, 9)
+            .amount(dec!(1.5))
+            .build();
+
+        assert_eq!(tx.typ(), &TransactionType::Withdrawal);
+        assert_eq!(tx.client(), 3);
+        assert_eq!(tx.tx(), 9);
+        assert_eq!(tx.amount(), Some(dec!(1.5)));
+        assert_eq!(
+            Transaction::builder(TransactionType::Dispute, 3, 9)
+                .build()
+                .amount(),
+            None
+        );
+    }
+
+    #[test]
+    fn deserialize_amount_should_be_exact() {
+        let csv = indoc!(
+            "type,client,tx,amount
+            deposit,1,1,0.1
+            deposit,1,2,1.23456789
+            deposit,1,3,2
+        "
+        );
+        let mut rdr = csv::ReaderBuilder::new().from_reader(csv.as_bytes());
+        let amounts: Vec<Option<Decimal>> = rdr
+            .deserialize()
+            .map(|r: Result<Transaction, _>| r.unwrap().amount)
+            .collect();
+
+        assert_eq!(
+            amounts,
+            vec![Some(dec!(0.1)), Some(dec!(1.23456789)), Some(dec!(2))]
+        );
+    }
+
+    #[test]
+    fn from_json_should_match_csv() {
+        let tx = from_json(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.10"}"#).unwrap();
+        assert_eq!(tx.typ, TransactionType::Deposit);
+        assert_eq!((tx.client, tx.tx), (1, 2));
+        assert_eq!(tx.amount.unwrap().to_string(), "1.10");
+
+        // numbers are exact too, and a missing or null amount is none.
+        let tx = from_json(r#"{"type":"withdrawal","client":1,"tx":3,"amount":0.1000}"#).unwrap();
+        assert_eq!(tx.amount.unwrap().to_string(), "0.1000");
+        let tx = from_json(r#"{"type":"dispute","client":1,"tx":2,"amount":null}"#).unwrap();
+        assert_eq!(tx.amount, None);
+        let tx = from_json(r#"{"type":"pirates_rock","client":1,"tx":2}"#).unwrap();
+        assert_eq!(tx.typ, TransactionType::Unknown("pirates_rock".into()));
+
+        assert!(from_json(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.1.1"}"#).is_err());
+        assert!(from_json(r#"{"type":"deposit","client":70000,"tx":2}"#).is_err());
+        assert!(from_json(r#"{"type":"deposit","#).is_err());
+    }
+
+    #[test]
+    fn deserialize_bad_amount_should_fail() {
+        let csv = indoc!(
+            "type,client,tx,amount
+            deposit,1,1,1.1.1
+            deposit,1,2,NaN
+        "
+        );
+        let mut rdr = csv::ReaderBuilder::new().from_reader(csv.as_bytes());
+        for result in rdr.deserialize::<Transaction>() {
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn dispute_state_should_only_follow_allowed_transitions() {
+        let next = |state: DisputeState, typ, closes, redispute| {
+            state.next(&typ, closes, redispute, 7).map_err(|e| e.code())
+        };
+        let after_resolve = RedisputePolicy::AfterResolve;
+
+        assert_eq!(
+            next(
+                DisputeState::Settled,
+                TransactionType::Dispute,
+                false,
+                after_resolve
+            ),
+            Ok(DisputeState::Disputed)
+        );
+        assert_eq!(
+            next(
+                DisputeState::Disputed,
+                TransactionType::Dispute,
+                false,
+                after_resolve
+            ),
+            Err("already_disputed")
+        );
+        // part of a resolve or chargeback leaves it disputed.
+        assert_eq!(
+            next(
+                DisputeState::Disputed,
+                TransactionType::Chargeback,
+                false,
+                after_resolve
+            ),
+            Ok(DisputeState::Disputed)
+        );
+        assert_eq!(
+            next(
+                DisputeState::Disputed,
+                TransactionType::Chargeback,
+                true,
+                after_resolve
+            ),
+            Ok(DisputeState::ChargedBack)
+        );
+        assert_eq!(
+            next(
+                DisputeState::Settled,
+                TransactionType::Resolve,
+                true,
+                after_resolve
+            ),
+            Err("not_disputed")
+        );
+        assert_eq!(
+            next(
+                DisputeState::Resolved,
+                TransactionType::Chargeback,
+                true,
+                after_resolve
+            ),
+            Err("dispute_closed")
+        );
+
+        // opening a closed dispute again is up to the policy.
+        for (redispute, resolved, charged_back) in [
+            (RedisputePolicy::Never, false, false),
+            (RedisputePolicy::AfterResolve, true, false),
+            (RedisputePolicy::Always, true, true),
+        ] {
+            let reopens = |state| next(state, TransactionType::Dispute, false, redispute).is_ok();
+            assert_eq!(reopens(DisputeState::Resolved), resolved);
+            assert_eq!(reopens(DisputeState::ChargedBack), charged_back);
+            assert_eq!(redispute.to_string().parse(), Ok(redispute));
+        }
+    }
 }